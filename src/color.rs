@@ -1,7 +1,10 @@
 extern crate rgb;
 
+use std::time::Duration;
+
 pub const BLACK: rgb::RGB8 = rgb::RGB8 { r: 0, g: 0, b: 0 };
 
+pub const BLUE: rgb::RGB8 = rgb::RGB8 { r: 0, g: 0, b: 255 };
 pub const GREEN: rgb::RGB8 = rgb::RGB8 { r: 0, g: 255, b: 0 };
 pub const RED: rgb::RGB8 = rgb::RGB8 { r: 255, g: 0, b: 0 };
 pub const WHITE: rgb::RGB8 = rgb::RGB8 {
@@ -10,10 +13,193 @@ pub const WHITE: rgb::RGB8 = rgb::RGB8 {
     b: 255,
 };
 
+/// Colors that may be referred to by name, e.g. in the `name` of a ColorAbsolute command.
+pub const NAMED_COLORS: &[(&str, rgb::RGB8)] = &[
+    ("black", BLACK),
+    ("blue", BLUE),
+    ("green", GREEN),
+    ("red", RED),
+    ("white", WHITE),
+    ("cyan", rgb::RGB8 { r: 0, g: 255, b: 255 }),
+    ("magenta", rgb::RGB8 { r: 255, g: 0, b: 255 }),
+    ("yellow", rgb::RGB8 { r: 255, g: 255, b: 0 }),
+    ("orange", rgb::RGB8 { r: 255, g: 165, b: 0 }),
+    ("pink", rgb::RGB8 { r: 255, g: 192, b: 203 }),
+    ("purple", rgb::RGB8 { r: 128, g: 0, b: 128 }),
+    ("turquoise", rgb::RGB8 { r: 64, g: 224, b: 208 }),
+    ("crimson", rgb::RGB8 { r: 220, g: 20, b: 60 }),
+    ("gold", rgb::RGB8 { r: 255, g: 215, b: 0 }),
+    ("lavender", rgb::RGB8 { r: 230, g: 230, b: 250 }),
+];
+
+/// Shades of white that may be referred to by name, as color temperatures in Kelvin.
+pub const NAMED_TEMPERATURES: &[(&str, u64)] = &[
+    ("warm white", 2700),
+    ("soft white", 3000),
+    ("cool white", 4000),
+    ("daylight", 5000),
+];
+
+/// Looks up a named color, ignoring case.
+pub fn rgb_by_name(name: &str) -> Option<rgb::RGB8> {
+    let name = name.to_lowercase();
+    NAMED_COLORS
+        .iter()
+        .find(|&&(n, _)| n == name)
+        .map(|&(_, c)| c)
+}
+
+/// Looks up a named shade of white, ignoring case.
+pub fn temperature_by_name(name: &str) -> Option<u64> {
+    let name = name.to_lowercase();
+    NAMED_TEMPERATURES
+        .iter()
+        .find(|&&(n, _)| n == name)
+        .map(|&(_, k)| k)
+}
+
+/// Parses a color given either by name or as `#RRGGBB`.
+pub fn parse(s: &str) -> Option<rgb::RGB8> {
+    if s.starts_with('#') && s.len() == 7 {
+        return u32::from_str_radix(&s[1..], 16).ok().map(|c| rgb::RGB8 {
+            r: (c >> 16) as u8,
+            g: (c >> 8) as u8,
+            b: c as u8,
+        });
+    }
+    rgb_by_name(s)
+}
+
+/// Returns the name of the given color, if it is exactly one of the named colors.
+pub fn rgb_name(c: rgb::RGB8) -> Option<&'static str> {
+    NAMED_COLORS
+        .iter()
+        .find(|&&(_, n)| n == c)
+        .map(|&(name, _)| name)
+}
+
+/// Returns the name of the given color temperature, if it is exactly one of the named shades.
+pub fn temperature_name(k: u64) -> Option<&'static str> {
+    NAMED_TEMPERATURES
+        .iter()
+        .find(|&&(_, n)| n == k)
+        .map(|&(name, _)| name)
+}
+
+/// Fraction of the difference to its target kept by a fading color every `P_INTERVAL_MS`.
 const P: f32 = 0.9;
+const P_INTERVAL_MS: f32 = 10.0;
+
+/// Approximates the color of a black body at the given temperature (in Kelvin), for lights that
+/// can only output RGB.
+///
+/// See http://www.tannerhelland.com/4435/convert-temperature-rgb-algorithm-code/.
+pub fn kelvin_to_rgb(k: u64) -> rgb::RGB8 {
+    let t = (k as f32 / 100.0).max(10.0).min(400.0);
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.698727446 * (t - 60.0).powf(-0.1332047592)
+    };
+    let g = if t <= 66.0 {
+        99.4708025861 * t.ln() - 161.1195681661
+    } else {
+        288.1221695283 * (t - 60.0).powf(-0.0755148492)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.5177312231 * (t - 10.0).ln() - 305.0447927307
+    };
+    rgb::RGB8 {
+        r: clamp(r),
+        g: clamp(g),
+        b: clamp(b),
+    }
+}
+
+/// Color in the HSV model: hue in degrees, saturation and value between 0 and 1.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Hsv {
+    pub hue: f32,
+    pub saturation: f32,
+    pub value: f32,
+}
+
+impl Hsv {
+    pub fn to_rgb(&self) -> rgb::RGB8 {
+        let h = (self.hue % 360.0 + 360.0) % 360.0 / 60.0;
+        let c = self.value * self.saturation;
+        let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+        let (r, g, b) = match h as u32 {
+            0 => (c, x, 0.0),
+            1 => (x, c, 0.0),
+            2 => (0.0, c, x),
+            3 => (0.0, x, c),
+            4 => (x, 0.0, c),
+            _ => (c, 0.0, x),
+        };
+        let m = self.value - c;
+        rgb::RGB8 {
+            r: clamp((r + m) * 255.0),
+            g: clamp((g + m) * 255.0),
+            b: clamp((b + m) * 255.0),
+        }
+    }
+
+    pub fn from_rgb(c: rgb::RGB8) -> Hsv {
+        let r = c.r as f32 / 255.0;
+        let g = c.g as f32 / 255.0;
+        let b = c.b as f32 / 255.0;
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let d = max - min;
+        let hue = if d == 0.0 {
+            0.0
+        } else if max == r {
+            60.0 * ((g - b) / d)
+        } else if max == g {
+            60.0 * ((b - r) / d + 2.0)
+        } else {
+            60.0 * ((r - g) / d + 4.0)
+        };
+        Hsv {
+            hue: if hue < 0.0 { hue + 360.0 } else { hue },
+            saturation: if max == 0.0 { 0.0 } else { d / max },
+            value: max,
+        }
+    }
+}
+
+fn clamp(x: f32) -> u8 {
+    x.max(0.0).min(255.0).round() as u8
+}
+
+/// Timing of a rendered frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    /// Time since the animation started.
+    pub elapsed: Duration,
+    /// Time since the previous frame.
+    pub delta: Duration,
+}
+
+impl Frame {
+    /// Returns the time since the animation started, in milliseconds.
+    pub fn elapsed_ms(&self) -> u64 {
+        millis(self.elapsed)
+    }
+}
+
+pub fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000
+}
 
 pub trait ColorFunc: Send + Sync {
-    fn step(&self, t: u64, current: &[rgb::RGB8]) -> [rgb::RGB8; 16];
+    /// Renders a frame into `pixels`, which hold the previous frame.
+    fn step(&self, frame: &Frame, pixels: &mut [rgb::RGB8]);
     fn color(&self) -> rgb::RGB8;
 }
 
@@ -22,12 +208,14 @@ pub struct SolidColor {
 }
 
 impl ColorFunc for SolidColor {
-    fn step(&self, _t: u64, current: &[rgb::RGB8]) -> [rgb::RGB8; 16] {
-        let mut pixels = [BLACK; 16];
-        for i in 0..pixels.len() {
-            pixels[i] = mean(current[i], self.c, P);
+    fn step(&self, frame: &Frame, pixels: &mut [rgb::RGB8]) {
+        // Fade at the same speed whatever the frame rate, including above 1000 frames per second.
+        let delta = frame.delta;
+        let delta_ms = (delta.as_secs() as f32 + delta.subsec_nanos() as f32 / 1e9) * 1000.0;
+        let p = P.powf(delta_ms / P_INTERVAL_MS);
+        for pixel in pixels.iter_mut() {
+            *pixel = mean(*pixel, self.c, p);
         }
-        pixels
     }
 
     fn color(&self) -> rgb::RGB8 {
@@ -43,53 +231,69 @@ fn mean(x: rgb::RGB8, y: rgb::RGB8, p: f32) -> rgb::RGB8 {
     }
 }
 
-pub struct ItalianFlag {}
-
-impl ColorFunc for ItalianFlag {
-    fn step(&self, _t: u64, _current: &[rgb::RGB8]) -> [rgb::RGB8; 16] {
-        [
-            GREEN, GREEN, GREEN, GREEN, GREEN, WHITE, WHITE, WHITE, WHITE, WHITE, WHITE, RED, RED,
-            RED, RED, RED,
-        ]
-    }
-
-    fn color(&self) -> rgb::RGB8 {
-        WHITE
-    }
+#[test]
+fn test_kelvin_to_rgb() {
+    assert_eq!(rgb::RGB8 { r: 255, g: 255, b: 255 }, kelvin_to_rgb(6600));
+    let warm = kelvin_to_rgb(2700);
+    assert_eq!(255, warm.r);
+    assert!(warm.g < 200 && warm.b < warm.g);
+    let cool = kelvin_to_rgb(9000);
+    assert_eq!(255, cool.b);
+    assert!(cool.r < 255);
 }
 
-pub struct Strobe {
-    pub period: u64,
+#[test]
+fn test_hsv() {
+    let hsv = Hsv {
+        hue: 120.0,
+        saturation: 1.0,
+        value: 1.0,
+    };
+    assert_eq!(GREEN, hsv.to_rgb());
+    assert_eq!(hsv, Hsv::from_rgb(GREEN));
+    assert_eq!(WHITE, Hsv::from_rgb(WHITE).to_rgb());
+    assert_eq!(
+        rgb::RGB8 { r: 3, g: 0, b: 3 },
+        Hsv {
+            hue: 300.0,
+            saturation: 1.0,
+            value: 0.01,
+        }.to_rgb()
+    );
 }
 
-impl ColorFunc for Strobe {
-    fn step(&self, t: u64, _current: &[rgb::RGB8]) -> [rgb::RGB8; 16] {
-        if t % (self.period * 2) < self.period {
-            [WHITE; 16]
-        } else {
-            [BLACK; 16]
-        }
-    }
-
-    fn color(&self) -> rgb::RGB8 {
-        WHITE
-    }
+#[test]
+fn test_named_colors() {
+    assert_eq!(Some(RED), rgb_by_name("Red"));
+    assert_eq!(Some(rgb::RGB8 { r: 64, g: 224, b: 208 }), rgb_by_name("turquoise"));
+    assert_eq!(None, rgb_by_name("warm white"));
+    assert_eq!(Some(2700), temperature_by_name("Warm White"));
+    assert_eq!(Some("green"), rgb_name(GREEN));
+    assert_eq!(None, rgb_name(rgb::RGB8 { r: 1, g: 2, b: 3 }));
+    assert_eq!(Some("daylight"), temperature_name(5000));
+    assert_eq!(None, temperature_name(5001));
+    assert_eq!(Some(rgb::RGB8 { r: 0x12, g: 0x34, b: 0x56 }), parse("#123456"));
+    assert_eq!(Some(RED), parse("red"));
+    assert_eq!(None, parse("#12345"));
 }
 
-pub struct Rainbow {
-    pub period: u64,
-}
+#[test]
+fn test_solid_color_fade() {
+    let red = SolidColor { c: RED };
+    let step = |delta: Duration, pixels: &mut [rgb::RGB8]| {
+        let frame = Frame {
+            elapsed: Duration::from_secs(0),
+            delta: delta,
+        };
+        red.step(&frame, pixels);
+    };
 
-impl ColorFunc for Rainbow {
-    fn step(&self, t: u64, _current: &[rgb::RGB8]) -> [rgb::RGB8; 16] {
-        if t % (self.period * 2) < self.period {
-            [WHITE; 16]
-        } else {
-            [BLACK; 16]
-        }
-    }
-
-    fn color(&self) -> rgb::RGB8 {
-        WHITE
-    }
+    let mut pixels = [BLACK];
+    step(Duration::from_millis(10), &mut pixels);
+    assert_eq!(25, pixels[0].r);
+    // Frames shorter than a millisecond still fade.
+    let mut pixels = [BLACK];
+    step(Duration::new(0, 500_000), &mut pixels);
+    assert_eq!(1, pixels[0].r);
+    assert_eq!(0, pixels[0].g);
 }
@@ -0,0 +1,582 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+
+use serde_json;
+use url::Url;
+
+use color;
+use device::Metadata;
+use google_actions::DeviceInfo;
+use homegraph::{HomeGraph, HomeGraphConfig};
+use hub::Hub;
+use hvac::{Activity, ControlConfig, Controller, OutputConfig};
+use light::{ColorModel, Light, LightMode, LightStatus, LightType, PixelRange, TemperatureRange};
+use oauth::OAuthConfig;
+use proxy::Proxy;
+use render::RenderConfig;
+use schedule::{Schedule, ScheduleStore, Slot};
+use sensor::{Sensor, SensorConfig};
+use scene::{LightTarget, Scene, SceneStore};
+use thermostat::{SetpointLimits, TemperatureUnit, Thermostat, ThermostatMode, ThermostatStatus};
+
+/// Declarative description of all the devices exposed by the hub.
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    #[serde(default)]
+    pub lights: Vec<LightConfig>,
+    #[serde(default)]
+    pub scenes: Vec<SceneConfig>,
+    #[serde(default)]
+    pub thermostats: Vec<ThermostatConfig>,
+    /// Downstream hubs whose devices are federated into this one.
+    #[serde(default)]
+    pub proxies: Vec<ProxyConfig>,
+    /// Account linking configuration.
+    #[serde(default)]
+    pub oauth: OAuthConfig,
+    /// HomeGraph API settings; if present, state changes are reported to Google.
+    #[serde(default)]
+    pub homegraph: Option<HomeGraphConfig>,
+    /// File where the state of lights is saved while reversible scenes are active.
+    #[serde(default = "default_scene_store")]
+    pub scene_store: String,
+    /// File where the schedules of thermostats replaced through the API are saved.
+    #[serde(default = "default_schedule_store")]
+    pub schedule_store: String,
+    /// Animation settings.
+    #[serde(default)]
+    pub render: RenderConfig,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LightConfig {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub nicknames: Vec<String>,
+    #[serde(default)]
+    pub default_names: Vec<String>,
+    #[serde(default)]
+    pub room_hint: Option<String>,
+    #[serde(default)]
+    pub structure_hint: Option<String>,
+    #[serde(default)]
+    pub device_info: Option<DeviceInfoConfig>,
+    #[serde(rename = "type", default = "default_light_type")]
+    pub type_: LightType,
+    pub traits: Vec<LightMode>,
+    #[serde(default)]
+    pub color_model: ColorModel,
+    #[serde(default)]
+    pub temperature_range: TemperatureRange,
+    pub pixels: Vec<PixelRange>,
+}
+
+/// Manufacturer details of a device, reported in SYNC as `deviceInfo`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeviceInfoConfig {
+    #[serde(default)]
+    pub manufacturer: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub hw_version: Option<String>,
+    #[serde(default)]
+    pub sw_version: Option<String>,
+}
+
+impl From<DeviceInfoConfig> for DeviceInfo {
+    fn from(c: DeviceInfoConfig) -> DeviceInfo {
+        DeviceInfo {
+            manufacturer: c.manufacturer,
+            model: c.model,
+            hw_version: c.hw_version,
+            sw_version: c.sw_version,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SceneConfig {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub nicknames: Vec<String>,
+    #[serde(default)]
+    pub default_names: Vec<String>,
+    #[serde(default)]
+    pub room_hint: Option<String>,
+    #[serde(default)]
+    pub structure_hint: Option<String>,
+    #[serde(default)]
+    pub device_info: Option<DeviceInfoConfig>,
+    #[serde(default)]
+    pub reversible: bool,
+    /// Lights affected by the scene, and the state to put them into.
+    pub lights: Vec<LightTarget>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ThermostatConfig {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub nicknames: Vec<String>,
+    #[serde(default)]
+    pub default_names: Vec<String>,
+    #[serde(default)]
+    pub room_hint: Option<String>,
+    #[serde(default)]
+    pub structure_hint: Option<String>,
+    #[serde(default)]
+    pub device_info: Option<DeviceInfoConfig>,
+    pub modes: Vec<ThermostatMode>,
+    /// Mode on startup; off if not given.
+    #[serde(default)]
+    pub mode: Option<ThermostatMode>,
+    #[serde(default = "default_temperature_unit")]
+    pub unit: TemperatureUnit,
+    #[serde(default = "default_setpoint")]
+    pub setpoint: f32,
+    #[serde(default = "default_setpoint_low")]
+    pub setpoint_low: f32,
+    #[serde(default = "default_setpoint_high")]
+    pub setpoint_high: f32,
+    #[serde(default = "default_min_setpoint")]
+    pub min_setpoint: f32,
+    #[serde(default = "default_max_setpoint")]
+    pub max_setpoint: f32,
+    #[serde(default = "default_min_setpoint_range")]
+    pub min_setpoint_range: f32,
+    #[serde(default)]
+    pub control: ControlConfig,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub temperature_sensor: Option<SensorConfig>,
+    #[serde(default)]
+    pub humidity_sensor: Option<SensorConfig>,
+    #[serde(default)]
+    pub schedule: Vec<Slot>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ProxyConfig {
+    /// URL of the fulfillment endpoint of the downstream hub.
+    pub url: String,
+    /// Access token issued by the downstream hub, if it requires one.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl ThermostatConfig {
+    fn limits(&self) -> SetpointLimits {
+        SetpointLimits {
+            min: self.min_setpoint,
+            max: self.max_setpoint,
+            min_range: self.min_setpoint_range,
+        }
+    }
+}
+
+macro_rules! metadata {
+    ($c:expr) => {
+        Metadata {
+            default_names: $c.default_names.clone(),
+            nicknames: $c.nicknames.clone(),
+            room_hint: $c.room_hint.clone(),
+            structure_hint: $c.structure_hint.clone(),
+            device_info: $c.device_info.clone().map(DeviceInfo::from),
+        }
+    };
+}
+
+fn default_scene_store() -> String {
+    "scenes.json".to_string()
+}
+
+fn default_schedule_store() -> String {
+    "schedules.json".to_string()
+}
+
+fn default_light_type() -> LightType {
+    LightType::Light
+}
+
+fn default_temperature_unit() -> TemperatureUnit {
+    TemperatureUnit::C
+}
+
+fn default_setpoint() -> f32 {
+    21.0
+}
+
+fn default_setpoint_low() -> f32 {
+    10.0
+}
+
+fn default_setpoint_high() -> f32 {
+    30.0
+}
+
+fn default_min_setpoint() -> f32 {
+    5.0
+}
+
+fn default_max_setpoint() -> f32 {
+    35.0
+}
+
+fn default_min_setpoint_range() -> f32 {
+    1.0
+}
+
+/// Reads and validates the configuration file at the given path.
+pub fn load(path: &str) -> Result<Config, String> {
+    let file = File::open(path).map_err(|e| format!("could not open {}: {}", path, e))?;
+    let config: Config =
+        serde_json::from_reader(file).map_err(|e| format!("could not parse {}: {}", path, e))?;
+    config.validate()?;
+    Ok(config)
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        self.render.validate()?;
+        let mut ids = BTreeSet::new();
+        let all_ids = self.lights
+            .iter()
+            .map(|l| &l.id)
+            .chain(self.scenes.iter().map(|s| &s.id))
+            .chain(self.thermostats.iter().map(|t| &t.id));
+        for id in all_ids {
+            if id.is_empty() {
+                return Err("device id must not be empty".to_string());
+            }
+            if !ids.insert(id.clone()) {
+                return Err(format!("duplicate device id: {:?}", id));
+            }
+        }
+
+        let mut used_pixels: Vec<Vec<bool>> = self.render
+            .channels
+            .iter()
+            .map(|&count| vec![false; count])
+            .collect();
+        for light in &self.lights {
+            if light.traits.is_empty() {
+                return Err(format!("light {:?} has no traits", light.id));
+            }
+            if light.temperature_range.min_k > light.temperature_range.max_k {
+                return Err(format!("light {:?} has an empty temperature range", light.id));
+            }
+            if light.pixels.iter().all(|r| r.count == 0) {
+                return Err(format!("light {:?} has no pixels", light.id));
+            }
+            for r in &light.pixels {
+                let used = used_pixels.get_mut(r.channel).ok_or(format!(
+                    "light {:?} refers to unknown channel {}",
+                    light.id, r.channel
+                ))?;
+                if r.offset + r.count > used.len() {
+                    return Err(format!(
+                        "light {:?} pixel range exceeds the {} pixels of channel {}",
+                        light.id,
+                        used.len(),
+                        r.channel
+                    ));
+                }
+                for i in r.offset..r.offset + r.count {
+                    if used[i] {
+                        return Err(format!(
+                            "light {:?} overlaps pixel {} of channel {}",
+                            light.id, i, r.channel
+                        ));
+                    }
+                    used[i] = true;
+                }
+            }
+        }
+
+        for scene in &self.scenes {
+            for target in &scene.lights {
+                if !self.lights.iter().any(|l| l.id == target.id) {
+                    return Err(format!(
+                        "scene {:?} refers to unknown light {:?}",
+                        scene.id, target.id
+                    ));
+                }
+                target
+                    .validate()
+                    .map_err(|e| format!("scene {:?}: {}", scene.id, e))?;
+            }
+        }
+
+        if let Some(ref homegraph) = self.homegraph {
+            Url::parse(&homegraph.url).map_err(|e| {
+                format!("invalid homegraph url {:?}: {}", homegraph.url, e)
+            })?;
+        }
+
+        for proxy in &self.proxies {
+            Url::parse(&proxy.url)
+                .map_err(|e| format!("invalid proxy url {:?}: {}", proxy.url, e))?;
+        }
+
+        for thermostat in &self.thermostats {
+            if thermostat.modes.is_empty() {
+                return Err(format!("thermostat {:?} has no modes", thermostat.id));
+            }
+            if let Some(ref mode) = thermostat.mode {
+                if !thermostat.modes.contains(mode) {
+                    return Err(format!(
+                        "thermostat {:?} mode {:?} is not one of its modes",
+                        thermostat.id,
+                        mode.to_string()
+                    ));
+                }
+            }
+            let limits = thermostat.limits();
+            if limits.min > limits.max {
+                return Err(format!(
+                    "thermostat {:?} has min_setpoint above max_setpoint",
+                    thermostat.id
+                ));
+            }
+            if limits.check(thermostat.setpoint).is_err() {
+                return Err(format!(
+                    "thermostat {:?} setpoint is outside the allowed range",
+                    thermostat.id
+                ));
+            }
+            if limits
+                .check_range(thermostat.setpoint_low, thermostat.setpoint_high)
+                .is_err()
+            {
+                return Err(format!(
+                    "thermostat {:?} setpoint_low and setpoint_high must be within the allowed \
+                     range and at least min_setpoint_range apart",
+                    thermostat.id
+                ));
+            }
+            if thermostat
+                .schedule
+                .iter()
+                .any(|slot| limits.check(slot.setpoint).is_err())
+            {
+                return Err(format!(
+                    "thermostat {:?} schedule has a setpoint outside the allowed range",
+                    thermostat.id
+                ));
+            }
+            thermostat
+                .output
+                .validate()
+                .map_err(|e| format!("thermostat {:?}: {}", thermostat.id, e))?;
+            Schedule::new(thermostat.schedule.clone())
+                .map_err(|e| format!("thermostat {:?} schedule: {}", thermostat.id, e))?;
+            for sensor in thermostat
+                .temperature_sensor
+                .iter()
+                .chain(thermostat.humidity_sensor.iter())
+            {
+                sensor
+                    .validate()
+                    .map_err(|e| format!("thermostat {:?}: {}", thermostat.id, e))?;
+            }
+        }
+
+        self.oauth.validate()
+    }
+
+    /// Instantiates the devices described by the configuration.
+    pub fn build(&self) -> Result<Hub, String> {
+        let homegraph = self.homegraph
+            .clone()
+            .map(|c| Arc::new(HomeGraph::new(c)));
+        let mut hub = Hub {
+            devices: vec![],
+            lights: vec![],
+            thermostats: vec![],
+            proxies: self.proxies
+                .iter()
+                .map(|p| Proxy::new(&p.url, p.token.clone()))
+                .collect(),
+            homegraph: homegraph.clone(),
+            schedule_store: ScheduleStore::load(&self.schedule_store),
+            proxies_refreshed: Mutex::new(None),
+        };
+
+        for l in &self.lights {
+            let light = Arc::new(Mutex::new(Light {
+                id: l.id.clone(),
+                name: l.name.clone(),
+                metadata: metadata!(l),
+                status: LightStatus::default(),
+                type_: l.type_.clone(),
+                available_light_modes: l.traits.clone(),
+                color_model: l.color_model.clone(),
+                temperature_range: l.temperature_range.clone(),
+                pixels: l.pixels.clone(),
+                color_func: Box::new(color::SolidColor { c: color::BLACK }),
+                effect: None,
+                homegraph: homegraph.clone(),
+            }));
+            hub.devices.push(light.clone());
+            hub.lights.push(light);
+        }
+
+        let scene_store = Arc::new(SceneStore::load(&self.scene_store));
+        for s in &self.scenes {
+            let lights = s.lights
+                .iter()
+                .filter_map(|target| {
+                    hub.lights
+                        .iter()
+                        .find(|l| l.lock().unwrap().id == target.id)
+                        .map(|l| (l.clone(), target.clone()))
+                })
+                .collect();
+            let scene = Arc::new(Mutex::new(Scene {
+                id: s.id.clone(),
+                name: s.name.clone(),
+                metadata: metadata!(s),
+                reversible: s.reversible,
+                lights: lights,
+                store: scene_store.clone(),
+            }));
+            hub.devices.push(scene);
+        }
+
+        for t in &self.thermostats {
+            let configured = Schedule::new(t.schedule.clone())
+                .map_err(|e| format!("thermostat {:?} schedule: {}", t.id, e))?;
+            // A schedule replaced through the API takes precedence over the configured one.
+            let schedule = match hub.schedule_store.get(&t.id) {
+                Some(slots) => Schedule::with_limits(slots, &t.limits()).unwrap_or_else(|err| {
+                    error!("thermostat {:?} saved schedule: {}", t.id, err);
+                    configured
+                }),
+                None => configured,
+            };
+            let thermostat = Arc::new(Mutex::new(Thermostat {
+                id: t.id.clone(),
+                name: t.name.clone(),
+                metadata: metadata!(t),
+                available_thermostat_modes: t.modes.clone(),
+                thermostat_temperature_unit: t.unit.clone(),
+                limits: t.limits(),
+                status: ThermostatStatus {
+                    // Until the first readings arrive, if there are any sensors.
+                    online: t.temperature_sensor.is_none() && t.humidity_sensor.is_none(),
+                    mode: t.mode.clone().unwrap_or(ThermostatMode::Off),
+                    temperature_setpoint: t.setpoint,
+                    temperature_ambient: 20.0,
+                    temperature_setpoint_low: t.setpoint_low,
+                    temperature_setpoint_high: t.setpoint_high,
+                    humidity_ambient: 50.0,
+                    activity: Activity::Idle,
+                },
+                controller: Controller::new(t.control.clone()),
+                output: t.output
+                    .build()
+                    .map_err(|e| format!("thermostat {:?}: {}", t.id, e))?,
+                temperature_sensor: t.temperature_sensor.clone().map(Sensor::new),
+                humidity_sensor: t.humidity_sensor.clone().map(Sensor::new),
+                schedule: schedule,
+                homegraph: homegraph.clone(),
+                reported_ambient: None,
+            }));
+            hub.devices.push(thermostat.clone());
+            hub.thermostats.push(thermostat);
+        }
+
+        Ok(hub)
+    }
+}
+
+#[test]
+fn test_device_info() {
+    use testing::build_hub;
+
+    let hub = build_hub(
+        r#"
+{
+  "lights": [{
+    "id": "111",
+    "name": "Bedroom lights",
+    "device_info": {
+      "manufacturer": "Pimoroni",
+      "model": "Mote",
+      "hw_version": "1.0",
+      "sw_version": "2.1"
+    },
+    "traits": ["OnOff"],
+    "pixels": [{ "channel": 0, "offset": 0, "count": 16 }]
+  }]
+}
+"#,
+    );
+    let light = hub.lights[0].lock().unwrap();
+    assert_eq!(
+        Some(DeviceInfo {
+            manufacturer: Some("Pimoroni".to_string()),
+            model: Some("Mote".to_string()),
+            hw_version: Some("1.0".to_string()),
+            sw_version: Some("2.1".to_string()),
+        }),
+        light.metadata.device_info
+    );
+}
+
+#[test]
+fn test_validate() {
+    let validate = |json: String| serde_json::from_str::<Config>(&json).unwrap().validate();
+    let light = |id: &str, channel: usize, offset: usize| {
+        format!(
+            r#"{{ "id": {:?}, "name": "Light", "traits": ["OnOff"],
+                  "pixels": [{{ "channel": {}, "offset": {}, "count": 8 }}] }}"#,
+            id, channel, offset
+        )
+    };
+    let lights = |a: String, b: String| format!(r#"{{ "lights": [{}, {}] }}"#, a, b);
+
+    assert_eq!(Ok(()), validate(lights(light("1", 0, 0), light("2", 0, 8))));
+    assert_eq!(
+        Err("duplicate device id: \"1\"".to_string()),
+        validate(lights(light("1", 0, 0), light("1", 1, 0)))
+    );
+    assert_eq!(
+        Err("light \"2\" overlaps pixel 4 of channel 0".to_string()),
+        validate(lights(light("1", 0, 0), light("2", 0, 4)))
+    );
+    assert_eq!(
+        Err("light \"2\" refers to unknown channel 4".to_string()),
+        validate(lights(light("1", 0, 0), light("2", 4, 0)))
+    );
+    assert_eq!(
+        Err("light \"2\" pixel range exceeds the 16 pixels of channel 0".to_string()),
+        validate(lights(light("1", 0, 0), light("2", 0, 12)))
+    );
+    assert_eq!(
+        Err("scene \"2\" refers to unknown light \"3\"".to_string()),
+        validate(format!(
+            r#"{{ "lights": [{}],
+                  "scenes": [{{ "id": "2", "name": "Scene", "lights": [{{ "id": "3" }}] }}] }}"#,
+            light("1", 0, 0)
+        ))
+    );
+    assert_eq!(
+        Err("thermostat \"3\" has no modes".to_string()),
+        validate(r#"{ "thermostats": [{ "id": "3", "name": "T", "modes": [] }] }"#.to_string())
+    );
+    assert_eq!(
+        Err("thermostat \"3\" mode \"heat\" is not one of its modes".to_string()),
+        validate(
+            r#"{ "thermostats": [{ "id": "3", "name": "T", "modes": ["off"], "mode": "heat" }] }"#
+                .to_string()
+        )
+    );
+    assert!(load("/nonexistent/config.json").is_err());
+}
@@ -1,8 +1,32 @@
-use google_actions::{ExecuteResponseCommand, Params, SyncResponseDevice};
+use google_actions::{DeviceCommand, DeviceInfo, ErrorCode, Name, Params, SyncResponseDevice};
 
 pub trait Device: Send + Sync {
     fn id(&self) -> String;
-    fn sync(&self) -> Option<SyncResponseDevice>;
-    fn query(&self) -> Option<Params>;
-    fn execute(&mut self, &Params) -> Option<ExecuteResponseCommand>;
+    fn sync(&self) -> SyncResponseDevice;
+    /// Returns the current state of the device.
+    fn query(&self) -> Result<Params, ErrorCode>;
+    /// Executes the given command, returning the resulting state of the device.
+    ///
+    /// Commands for traits not declared in SYNC are rejected before reaching the device.
+    fn execute(&mut self, &DeviceCommand) -> Result<Params, ErrorCode>;
+}
+
+/// Names and hints reported in SYNC, which let users refer to a device by alias or by room.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub default_names: Vec<String>,
+    pub nicknames: Vec<String>,
+    pub room_hint: Option<String>,
+    pub structure_hint: Option<String>,
+    pub device_info: Option<DeviceInfo>,
+}
+
+impl Metadata {
+    pub fn name(&self, name: &str) -> Name {
+        Name {
+            default_names: self.default_names.clone(),
+            name: Some(name.to_string()),
+            nicknames: self.nicknames.clone(),
+        }
+    }
 }
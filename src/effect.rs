@@ -0,0 +1,547 @@
+extern crate rgb;
+
+use serde::{Deserialize, Deserializer};
+use std::f32::consts::PI;
+
+use color;
+use color::{ColorFunc, Frame, Hsv, BLACK, GREEN, RED, WHITE};
+
+/// Animated effect, selectable by name from the configuration, e.g.
+/// `{"name": "comet", "period_ms": 1500, "palette": ["turquoise"], "direction": "reverse"}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Effect {
+    pub name: EffectName,
+    /// Duration of one cycle of the effect, in milliseconds.
+    #[serde(default = "default_period_ms")]
+    pub period_ms: u64,
+    /// Colors used by the effect, by name or as `#RRGGBB`; each effect has its own default.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub palette: Vec<String>,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+fn default_period_ms() -> u64 {
+    2000
+}
+
+/// Effect as written in scenes: either all its settings, or, as a shorthand, only its name (e.g.
+/// `"rainbow"`) to use the defaults.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EffectSpec {
+    Name(EffectName),
+    Effect(Effect),
+}
+
+/// Deserializes an optional effect, given in either of the forms of `EffectSpec`.
+pub fn deserialize_optional<'de, D>(deserializer: D) -> Result<Option<Effect>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let spec = Option::<EffectSpec>::deserialize(deserializer)?;
+    Ok(spec.map(|spec| match spec {
+        EffectSpec::Name(name) => Effect {
+            name: name,
+            period_ms: default_period_ms(),
+            palette: vec![],
+            direction: Direction::default(),
+        },
+        EffectSpec::Effect(effect) => effect,
+    }))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EffectName {
+    /// Hues cycling along the pixels.
+    Rainbow,
+    /// All pixels fading in and out, one palette color per cycle.
+    Breathe,
+    /// Every third pixel lit, marching along.
+    TheaterChase,
+    /// A bright head with a fading tail, running around.
+    Comet,
+    /// Pixels lighting up and fading out at random.
+    Twinkle,
+    /// Flickering flames, hottest at the start of the pixels; the palette is a gradient from cold
+    /// to hot.
+    Fire,
+    /// Pixels changing to the next palette color one after the other.
+    ColorWipe,
+    /// All pixels flashing.
+    Strobe,
+    ItalianFlag,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Forward,
+    Reverse,
+}
+
+impl Default for Direction {
+    fn default() -> Direction {
+        Direction::Forward
+    }
+}
+
+impl Effect {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.period_ms == 0 {
+            return Err("effect period_ms must be positive".to_string());
+        }
+        for c in &self.palette {
+            color::parse(c).ok_or(format!("invalid effect color {:?}", c))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the colors of the palette, or the default ones if none is valid, as effects restored
+    /// from saved scenes are not validated.
+    fn palette(&self) -> Vec<rgb::RGB8> {
+        let colors: Vec<rgb::RGB8> = self.palette.iter().filter_map(|c| color::parse(c)).collect();
+        if !colors.is_empty() {
+            return colors;
+        }
+        let default: &[&str] = match self.name {
+            EffectName::Fire => &["black", "red", "orange", "yellow", "white"],
+            EffectName::Breathe | EffectName::ColorWipe | EffectName::Twinkle => {
+                &["red", "green", "blue"]
+            }
+            _ => &["white"],
+        };
+        default.iter().filter_map(|c| color::parse(c)).collect()
+    }
+
+    pub fn color_func(&self) -> Box<ColorFunc> {
+        let period = self.period_ms.max(1);
+        let colors = self.palette();
+        let f: Box<ColorFunc> = match self.name {
+            EffectName::Rainbow => Box::new(Rainbow { period_ms: period }),
+            EffectName::Breathe => Box::new(Breathe {
+                period_ms: period,
+                colors: colors,
+            }),
+            EffectName::TheaterChase => Box::new(TheaterChase {
+                period_ms: period,
+                colors: colors,
+            }),
+            EffectName::Comet => Box::new(Comet {
+                period_ms: period,
+                color: colors[0],
+            }),
+            EffectName::Twinkle => Box::new(Twinkle {
+                period_ms: period,
+                colors: colors,
+            }),
+            EffectName::Fire => Box::new(Fire {
+                period_ms: period,
+                colors: colors,
+            }),
+            EffectName::ColorWipe => Box::new(ColorWipe {
+                period_ms: period,
+                colors: colors,
+            }),
+            EffectName::Strobe => Box::new(Strobe {
+                period_ms: period,
+                color: colors[0],
+            }),
+            EffectName::ItalianFlag => Box::new(ItalianFlag {}),
+        };
+        match self.direction {
+            Direction::Forward => f,
+            Direction::Reverse => Box::new(Reversed { inner: f }),
+        }
+    }
+}
+
+/// Fraction of the current cycle elapsed at `t` milliseconds.
+fn phase(t: u64, period: u64) -> f32 {
+    (t % period) as f32 / period as f32
+}
+
+fn scale(c: rgb::RGB8, f: f32) -> rgb::RGB8 {
+    lerp(BLACK, c, f)
+}
+
+fn lerp(a: rgb::RGB8, b: rgb::RGB8, f: f32) -> rgb::RGB8 {
+    let f = f.max(0.0).min(1.0);
+    let mix = |x: u8, y: u8| (x as f32 + (y as f32 - x as f32) * f).round() as u8;
+    rgb::RGB8 {
+        r: mix(a.r, b.r),
+        g: mix(a.g, b.g),
+        b: mix(a.b, b.b),
+    }
+}
+
+/// Color at position `f` (between 0 and 1) of a gradient through the given colors.
+fn gradient(colors: &[rgb::RGB8], f: f32) -> rgb::RGB8 {
+    if colors.len() == 1 {
+        return scale(colors[0], f);
+    }
+    let x = f.max(0.0).min(1.0) * (colors.len() - 1) as f32;
+    let i = (x as usize).min(colors.len() - 2);
+    lerp(colors[i], colors[i + 1], x - i as f32)
+}
+
+/// Deterministic pseudo-random number, so that effects need no mutable state (SplitMix64).
+fn hash(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Pseudo-random number between 0 and 1.
+fn noise(x: u64) -> f32 {
+    (hash(x) % 1024) as f32 / 1023.0
+}
+
+pub struct Rainbow {
+    pub period_ms: u64,
+}
+
+impl ColorFunc for Rainbow {
+    fn step(&self, frame: &Frame, pixels: &mut [rgb::RGB8]) {
+        let p = phase(frame.elapsed_ms(), self.period_ms);
+        let n = pixels.len() as f32;
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = Hsv {
+                hue: (p + i as f32 / n) * 360.0 % 360.0,
+                saturation: 1.0,
+                value: 1.0,
+            }.to_rgb();
+        }
+    }
+
+    fn color(&self) -> rgb::RGB8 {
+        WHITE
+    }
+}
+
+pub struct Breathe {
+    pub period_ms: u64,
+    pub colors: Vec<rgb::RGB8>,
+}
+
+impl ColorFunc for Breathe {
+    fn step(&self, frame: &Frame, pixels: &mut [rgb::RGB8]) {
+        let t = frame.elapsed_ms();
+        let c = self.colors[(t / self.period_ms) as usize % self.colors.len()];
+        let level = (1.0 - (2.0 * PI * phase(t, self.period_ms)).cos()) / 2.0;
+        fill(pixels, scale(c, level));
+    }
+
+    fn color(&self) -> rgb::RGB8 {
+        self.colors[0]
+    }
+}
+
+pub struct TheaterChase {
+    pub period_ms: u64,
+    pub colors: Vec<rgb::RGB8>,
+}
+
+impl ColorFunc for TheaterChase {
+    fn step(&self, frame: &Frame, pixels: &mut [rgb::RGB8]) {
+        let t = frame.elapsed_ms();
+        let c = self.colors[(t / self.period_ms) as usize % self.colors.len()];
+        let offset = (phase(t, self.period_ms) * 3.0) as usize;
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = if (i + 3 - offset) % 3 == 0 { c } else { BLACK };
+        }
+    }
+
+    fn color(&self) -> rgb::RGB8 {
+        self.colors[0]
+    }
+}
+
+pub struct Comet {
+    pub period_ms: u64,
+    pub color: rgb::RGB8,
+}
+
+/// Length of the tail of a comet, as a fraction of the pixels.
+const COMET_TAIL: f32 = 0.375;
+
+impl ColorFunc for Comet {
+    fn step(&self, frame: &Frame, pixels: &mut [rgb::RGB8]) {
+        let n = pixels.len() as f32;
+        let head = (phase(frame.elapsed_ms(), self.period_ms) * n).floor();
+        let tail = (COMET_TAIL * n).max(1.0);
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let distance = (head - i as f32 + n) % n;
+            *pixel = if distance < tail {
+                scale(self.color, (1.0 - distance / tail).powi(2))
+            } else {
+                BLACK
+            };
+        }
+    }
+
+    fn color(&self) -> rgb::RGB8 {
+        self.color
+    }
+}
+
+pub struct Twinkle {
+    pub period_ms: u64,
+    pub colors: Vec<rgb::RGB8>,
+}
+
+impl ColorFunc for Twinkle {
+    fn step(&self, frame: &Frame, pixels: &mut [rgb::RGB8]) {
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            // Each pixel runs its own cycle, offset at random, and only lights up in some cycles.
+            let t = frame.elapsed_ms() + hash(i as u64) % self.period_ms;
+            let cycle = hash((t / self.period_ms) << 16 | i as u64);
+            *pixel = if cycle % 3 == 0 {
+                let c = self.colors[(cycle / 3) as usize % self.colors.len()];
+                scale(c, (PI * phase(t, self.period_ms)).sin().powi(2))
+            } else {
+                BLACK
+            };
+        }
+    }
+
+    fn color(&self) -> rgb::RGB8 {
+        self.colors[0]
+    }
+}
+
+pub struct Fire {
+    pub period_ms: u64,
+    pub colors: Vec<rgb::RGB8>,
+}
+
+/// Number of random flickers of each pixel of a fire per period.
+const FIRE_FLICKERS: u64 = 8;
+
+impl ColorFunc for Fire {
+    fn step(&self, frame: &Frame, pixels: &mut [rgb::RGB8]) {
+        let x = frame.elapsed_ms() * FIRE_FLICKERS;
+        let k = x / self.period_ms;
+        let f = (x % self.period_ms) as f32 / self.period_ms as f32;
+        let n = pixels.len() as f32;
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let seed = (i as u64) << 32;
+            let flicker = noise(seed | k) * (1.0 - f) + noise(seed | (k + 1)) * f;
+            let base = 1.0 - i as f32 / n;
+            *pixel = gradient(&self.colors, base * (0.4 + 0.6 * flicker));
+        }
+    }
+
+    fn color(&self) -> rgb::RGB8 {
+        RED
+    }
+}
+
+pub struct ColorWipe {
+    pub period_ms: u64,
+    pub colors: Vec<rgb::RGB8>,
+}
+
+impl ColorFunc for ColorWipe {
+    fn step(&self, frame: &Frame, pixels: &mut [rgb::RGB8]) {
+        let t = frame.elapsed_ms();
+        // With a single color, wipe it in and out.
+        let colors = if self.colors.len() == 1 {
+            vec![self.colors[0], BLACK]
+        } else {
+            self.colors.clone()
+        };
+        let cycle = (t / self.period_ms) as usize;
+        let next = colors[cycle % colors.len()];
+        let previous = colors[(cycle + colors.len() - 1) % colors.len()];
+        let progress = (phase(t, self.period_ms) * (pixels.len() + 1) as f32) as usize;
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = if i < progress { next } else { previous };
+        }
+    }
+
+    fn color(&self) -> rgb::RGB8 {
+        self.colors[0]
+    }
+}
+
+pub struct Strobe {
+    pub period_ms: u64,
+    pub color: rgb::RGB8,
+}
+
+impl ColorFunc for Strobe {
+    fn step(&self, frame: &Frame, pixels: &mut [rgb::RGB8]) {
+        if phase(frame.elapsed_ms(), self.period_ms) < 0.5 {
+            fill(pixels, self.color);
+        } else {
+            fill(pixels, BLACK);
+        }
+    }
+
+    fn color(&self) -> rgb::RGB8 {
+        self.color
+    }
+}
+
+pub struct ItalianFlag {}
+
+impl ColorFunc for ItalianFlag {
+    fn step(&self, _frame: &Frame, pixels: &mut [rgb::RGB8]) {
+        // 5 green, 6 white and 5 red pixels out of 16.
+        let n = pixels.len();
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = match i * 16 / n {
+                0...4 => GREEN,
+                5...10 => WHITE,
+                _ => RED,
+            };
+        }
+    }
+
+    fn color(&self) -> rgb::RGB8 {
+        WHITE
+    }
+}
+
+/// Runs another effect in the opposite direction.
+pub struct Reversed {
+    pub inner: Box<ColorFunc>,
+}
+
+impl ColorFunc for Reversed {
+    fn step(&self, frame: &Frame, pixels: &mut [rgb::RGB8]) {
+        pixels.reverse();
+        self.inner.step(frame, pixels);
+        pixels.reverse();
+    }
+
+    fn color(&self) -> rgb::RGB8 {
+        self.inner.color()
+    }
+}
+
+fn fill(pixels: &mut [rgb::RGB8], c: rgb::RGB8) {
+    for pixel in pixels.iter_mut() {
+        *pixel = c;
+    }
+}
+
+#[cfg(test)]
+fn effect(name: EffectName, palette: &[&str], direction: Direction) -> Box<ColorFunc> {
+    Effect {
+        name: name,
+        period_ms: 100,
+        palette: palette.iter().map(|c| c.to_string()).collect(),
+        direction: direction,
+    }.color_func()
+}
+
+/// Renders 16 pixels at `ms` milliseconds.
+#[cfg(test)]
+fn render(f: &Box<ColorFunc>, ms: u64) -> Vec<rgb::RGB8> {
+    use std::time::Duration;
+
+    let mut pixels = vec![BLACK; 16];
+    let frame = Frame {
+        elapsed: Duration::from_millis(ms),
+        delta: Duration::from_millis(10),
+    };
+    f.step(&frame, &mut pixels);
+    pixels
+}
+
+#[test]
+fn test_rainbow() {
+    let rainbow = effect(EffectName::Rainbow, &[], Direction::Forward);
+    let pixels = render(&rainbow, 0);
+    assert_eq!(RED, pixels[0]);
+    assert_eq!(Hsv::from_rgb(pixels[4]).hue.round(), 90.0);
+    assert_eq!(pixels[4], render(&rainbow, 25)[0]);
+    assert_eq!(pixels, render(&rainbow, 100));
+}
+
+#[test]
+fn test_comet() {
+    let comet = effect(EffectName::Comet, &["red"], Direction::Forward);
+    let pixels = render(&comet, 25);
+    assert_eq!(RED, pixels[4]);
+    assert!(pixels[3].r < 255 && pixels[3].r > pixels[2].r);
+    assert_eq!(BLACK, pixels[5]);
+
+    let comet = effect(EffectName::Comet, &["red"], Direction::Reverse);
+    assert_eq!(RED, render(&comet, 25)[11]);
+}
+
+#[test]
+fn test_breathe_and_wipe() {
+    let breathe = effect(EffectName::Breathe, &["red", "blue"], Direction::Forward);
+    assert_eq!(vec![BLACK; 16], render(&breathe, 0));
+    assert_eq!(vec![RED; 16], render(&breathe, 50));
+    assert_eq!(vec![color::BLUE; 16], render(&breathe, 150));
+
+    let wipe = effect(EffectName::ColorWipe, &["red"], Direction::Forward);
+    let pixels = render(&wipe, 50);
+    assert_eq!(RED, pixels[7]);
+    assert_eq!(BLACK, pixels[9]);
+    assert_eq!(vec![RED; 16], render(&wipe, 99));
+
+    for name in vec![EffectName::Twinkle, EffectName::Fire, EffectName::TheaterChase] {
+        let f = effect(name, &[], Direction::Forward);
+        assert_eq!(render(&f, 42), render(&f, 42));
+    }
+}
+
+#[test]
+fn test_pixel_count() {
+    use std::time::Duration;
+
+    let frame = Frame {
+        elapsed: Duration::from_millis(0),
+        delta: Duration::from_millis(10),
+    };
+    let mut pixels = vec![BLACK; 32];
+    effect(EffectName::ItalianFlag, &[], Direction::Forward).step(&frame, &mut pixels);
+    assert_eq!(vec![GREEN; 10], &pixels[..10]);
+    assert_eq!(vec![RED; 10], &pixels[22..]);
+
+    let mut pixels = vec![BLACK; 3];
+    effect(EffectName::Rainbow, &[], Direction::Forward).step(&frame, &mut pixels);
+    assert_eq!(vec![RED, GREEN, color::BLUE], pixels);
+}
+
+#[test]
+fn test_deserialize() {
+    use serde_json;
+
+    #[derive(Deserialize)]
+    struct Target {
+        #[serde(default)]
+        #[serde(deserialize_with = "deserialize_optional")]
+        effect: Option<Effect>,
+    }
+    let effect = |json| serde_json::from_str::<Target>(json).unwrap().effect;
+
+    assert_eq!(None, effect("{}"));
+    assert_eq!(
+        Some(Effect {
+            name: EffectName::ItalianFlag,
+            period_ms: 2000,
+            palette: vec![],
+            direction: Direction::Forward,
+        }),
+        effect(r#"{"effect": "italian_flag"}"#)
+    );
+    assert_eq!(
+        Some(vec!["red".to_string()]),
+        effect(r#"{"effect": {"name": "comet", "palette": ["red"]}}"#).map(|e| e.palette)
+    );
+    assert!(serde_json::from_str::<Target>(r#"{"effect": "sparkle"}"#).is_err());
+
+    // Invalid palettes fall back to the default one.
+    let breathe = effect(r#"{"effect": {"name": "breathe", "palette": ["nope"]}}"#).unwrap();
+    assert_eq!(vec![RED, GREEN, color::BLUE], breathe.palette());
+}
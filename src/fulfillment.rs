@@ -0,0 +1,589 @@
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+use serde_json;
+
+use google_actions::{ActionRequest, ActionRequestPayload, ActionResponse, Command,
+                     DeviceCommand, DisconnectResponse, ErrorCode, ErrorResponse,
+                     ErrorResponsePayload, ExecuteResponse, ExecuteResponseCommand,
+                     ExecuteResponsePayload, Params, QueryResponse, QueryResponsePayload,
+                     RequestDevice, Status, SyncResponse, SyncResponsePayload};
+use hub::Hub;
+use oauth::OAuth;
+#[cfg(test)]
+use testing::{build_hub, TempDir};
+
+/// Handles a fulfillment request on behalf of the given user.
+///
+/// Every input of the request is processed in order, and the responses to each of them are merged
+/// into a single response. Inputs with unknown intents, or with intents different from the first
+/// one, result in a `protocolError` response.
+pub fn fulfill(
+    hub: &Hub,
+    oauth: &OAuth,
+    user_id: &str,
+    request: ActionRequest,
+) -> ActionResponse {
+    let request_id = request.request_id;
+    let mut response = None;
+    for input in request.inputs {
+        let input_response = match input.intent.as_ref() {
+            "action.devices.SYNC" => sync(hub, &request_id, user_id),
+            "action.devices.QUERY" => query(hub, &request_id, input.payload),
+            "action.devices.EXECUTE" => execute(hub, &request_id, input.payload),
+            "action.devices.DISCONNECT" => disconnect(hub, oauth, user_id),
+            i => {
+                debug!("unsupported intent: {:?}", i);
+                error(
+                    &request_id,
+                    ErrorCode::ProtocolError,
+                    &format!("unsupported intent: {}", i),
+                )
+            }
+        };
+        response = Some(match response {
+            None => input_response,
+            Some(response) => merge(&request_id, response, input_response),
+        });
+    }
+    response.unwrap_or_else(|| error(&request_id, ErrorCode::ProtocolError, "no inputs"))
+}
+
+fn error(request_id: &str, error_code: ErrorCode, debug_string: &str) -> ActionResponse {
+    ActionResponse::Error(ErrorResponse {
+        request_id: request_id.to_string(),
+        payload: ErrorResponsePayload {
+            error_code: error_code,
+            debug_string: Some(debug_string.to_string()),
+        },
+    })
+}
+
+fn merge(request_id: &str, a: ActionResponse, b: ActionResponse) -> ActionResponse {
+    match (a, b) {
+        (e @ ActionResponse::Error(_), _) | (_, e @ ActionResponse::Error(_)) => e,
+        (ActionResponse::Sync(mut a), ActionResponse::Sync(b)) => {
+            a.payload.devices.extend(b.payload.devices);
+            ActionResponse::Sync(a)
+        }
+        (ActionResponse::Query(mut a), ActionResponse::Query(b)) => {
+            a.payload.devices.extend(b.payload.devices);
+            ActionResponse::Query(a)
+        }
+        (ActionResponse::Execute(mut a), ActionResponse::Execute(b)) => {
+            a.payload.commands.extend(b.payload.commands);
+            ActionResponse::Execute(a)
+        }
+        (ActionResponse::Disconnect(a), ActionResponse::Disconnect(_)) => {
+            ActionResponse::Disconnect(a)
+        }
+        _ => error(request_id, ErrorCode::ProtocolError, "inputs with different intents"),
+    }
+}
+
+fn sync(hub: &Hub, request_id: &str, user_id: &str) -> ActionResponse {
+    let mut response = SyncResponse {
+        request_id: request_id.to_string(),
+        payload: SyncResponsePayload {
+            agent_user_id: user_id.to_string(),
+            devices: vec![],
+        },
+    };
+
+    for device in &hub.devices {
+        let device = device.lock().unwrap();
+        response.payload.devices.push(device.sync());
+    }
+
+    if let Some(ref homegraph) = hub.homegraph {
+        homegraph.add_user(user_id);
+    }
+
+    for proxy in &hub.proxies {
+        match proxy.sync(request_id) {
+            Ok(devices) => response.payload.devices.extend(devices),
+            Err(err) => error!("could not sync proxy {}: {}", proxy.url, err),
+        }
+    }
+
+    ActionResponse::Sync(response)
+}
+
+fn query(hub: &Hub, request_id: &str, payload: Option<ActionRequestPayload>) -> ActionResponse {
+    let mut response = QueryResponse {
+        request_id: request_id.to_string(),
+        payload: QueryResponsePayload {
+            devices: btreemap!{},
+        },
+    };
+
+    if let Some(payload) = payload {
+        let owners = hub.proxy_owners(request_id, &remote_ids(hub, &payload.devices));
+        let mut proxied = BTreeMap::new();
+        for request_device in payload.devices {
+            if let Some(device) = hub.device(&request_device.id) {
+                let device = device.lock().unwrap();
+                let state = match device.query() {
+                    Ok(state) => Params {
+                        status: Some(Status::Success),
+                        ..state
+                    },
+                    Err(error_code) => error_state(error_code),
+                };
+                response.payload.devices.insert(device.id(), state);
+                continue;
+            }
+            match owners.get(&request_device.id) {
+                Some(&i) => proxied.entry(i).or_insert(vec![]).push(request_device),
+                None => {
+                    debug!("unknown device: {:?}", request_device.id);
+                    response
+                        .payload
+                        .devices
+                        .insert(request_device.id, error_state(ErrorCode::DeviceNotFound));
+                }
+            }
+        }
+
+        for (i, devices) in proxied {
+            let proxy = &hub.proxies[i];
+            let ids: Vec<String> = devices.iter().map(|d| d.id.clone()).collect();
+            match proxy.query(request_id, devices) {
+                Ok(devices) => response.payload.devices.extend(devices),
+                Err(err) => {
+                    error!("could not query proxy {}: {}", proxy.url, err);
+                    for id in ids {
+                        response
+                            .payload
+                            .devices
+                            .insert(id, error_state(ErrorCode::DeviceOffline));
+                    }
+                }
+            }
+        }
+    }
+
+    ActionResponse::Query(response)
+}
+
+fn execute(hub: &Hub, request_id: &str, payload: Option<ActionRequestPayload>) -> ActionResponse {
+    let mut response = ExecuteResponse {
+        request_id: request_id.to_string(),
+        payload: ExecuteResponsePayload {
+            error_code: None,
+            debug_string: None,
+            commands: vec![],
+        },
+    };
+
+    if let Some(payload) = payload {
+        let request_devices: Vec<RequestDevice> = payload
+            .commands
+            .iter()
+            .flat_map(|c| c.devices.iter().cloned())
+            .collect();
+        let owners = hub.proxy_owners(request_id, &remote_ids(hub, &request_devices));
+        let mut proxied = BTreeMap::new();
+        for command in &payload.commands {
+            debug!("command: {:?}", command);
+            let mut proxied_devices = BTreeMap::new();
+            for request_device in &command.devices {
+                debug!("request_device: {:?}", request_device);
+                if let Some(device) = hub.device(&request_device.id) {
+                    let mut device = device.lock().unwrap();
+                    let traits = device.sync().traits;
+                    for execution in &command.execution {
+                        debug!("execution: {:?}", execution);
+                        let result = DeviceCommand::from_execution(execution).and_then(|c| {
+                            if c.required_traits().iter().any(|r| traits.iter().any(|t| t == r)) {
+                                device.execute(&c)
+                            } else {
+                                Err(ErrorCode::NotSupported)
+                            }
+                        });
+                        response
+                            .payload
+                            .commands
+                            .push(execute_response_command(&request_device.id, result));
+                    }
+                    continue;
+                }
+                match owners.get(&request_device.id) {
+                    Some(&i) => proxied_devices
+                        .entry(i)
+                        .or_insert(vec![])
+                        .push(request_device.clone()),
+                    None => {
+                        debug!("unknown device: {:?}", request_device.id);
+                        response.payload.commands.push(execute_response_command(
+                            &request_device.id,
+                            Err(ErrorCode::DeviceNotFound),
+                        ));
+                    }
+                }
+            }
+            for (i, devices) in proxied_devices {
+                proxied.entry(i).or_insert(vec![]).push(Command {
+                    devices: devices,
+                    execution: command.execution.clone(),
+                });
+            }
+        }
+
+        for (i, commands) in proxied {
+            let proxy = &hub.proxies[i];
+            let ids: Vec<String> = commands
+                .iter()
+                .flat_map(|c| c.devices.iter().map(|d| d.id.clone()))
+                .collect();
+            match proxy.execute(request_id, commands) {
+                Ok(commands) => response.payload.commands.extend(commands),
+                Err(err) => {
+                    error!("could not execute on proxy {}: {}", proxy.url, err);
+                    for id in ids {
+                        response.payload.commands.push(execute_response_command(
+                            &id,
+                            Err(ErrorCode::DeviceOffline),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    ActionResponse::Execute(response)
+}
+
+/// Returns the ids of the devices that are not local, and so may be owned by a proxy.
+fn remote_ids(hub: &Hub, devices: &[RequestDevice]) -> Vec<String> {
+    devices
+        .iter()
+        .filter(|d| hub.device(&d.id).is_none())
+        .map(|d| d.id.clone())
+        .collect()
+}
+
+/// State reported by QUERY for a device that could not be queried.
+fn error_state(error_code: ErrorCode) -> Params {
+    Params {
+        online: Some(error_code != ErrorCode::DeviceOffline),
+        status: Some(error_code.status()),
+        error_code: Some(error_code),
+        ..Params::default()
+    }
+}
+
+fn execute_response_command(
+    id: &str,
+    result: Result<Params, ErrorCode>,
+) -> ExecuteResponseCommand {
+    match result {
+        Ok(states) => ExecuteResponseCommand {
+            ids: vec![id.to_string()],
+            status: Status::Success,
+            error_code: None,
+            states: states,
+        },
+        Err(error_code) => ExecuteResponseCommand {
+            ids: vec![id.to_string()],
+            status: error_code.status(),
+            error_code: Some(error_code),
+            states: Params::default(),
+        },
+    }
+}
+
+fn disconnect(hub: &Hub, oauth: &OAuth, user_id: &str) -> ActionResponse {
+    info!("disconnecting user {:?}", user_id);
+    oauth.revoke_user(user_id);
+    if let Some(ref homegraph) = hub.homegraph {
+        homegraph.remove_user(user_id);
+    }
+
+    ActionResponse::Disconnect(DisconnectResponse {})
+}
+
+#[cfg(test)]
+fn test_hub() -> Hub {
+    build_hub(
+        r#"
+{
+  "lights": [{
+    "id": "111",
+    "name": "Bedroom lights",
+    "traits": ["OnOff", "Brightness"],
+    "pixels": [{ "channel": 0, "offset": 0, "count": 16 }]
+  }]
+}
+"#,
+    )
+}
+
+#[cfg(test)]
+fn test_oauth(dir: &TempDir) -> OAuth {
+    use oauth::OAuthConfig;
+
+    OAuth::new(OAuthConfig {
+        token_store: dir.file("tokens.json"),
+        ..OAuthConfig::default()
+    }).unwrap()
+}
+
+/// Fulfills the given JSON request against `test_hub`, on behalf of "user".
+#[cfg(test)]
+fn fulfill_json(json: &str) -> ActionResponse {
+    fulfill_with(&test_hub(), json)
+}
+
+/// Fulfills the given JSON request against the given hub, on behalf of "user".
+#[cfg(test)]
+fn fulfill_with(hub: &Hub, json: &str) -> ActionResponse {
+    let dir = TempDir::new("fulfill");
+    fulfill(hub, &test_oauth(&dir), "user", serde_json::from_str(json).unwrap())
+}
+
+#[test]
+fn test_fulfill_all_inputs() {
+    let response = fulfill_json(
+        r#"
+{
+  "requestId": "1",
+  "inputs": [{
+    "intent": "action.devices.EXECUTE",
+    "payload": {
+      "commands": [{
+        "devices": [{ "id": "111" }],
+        "execution": [{ "command": "action.devices.commands.OnOff", "params": { "on": true } }]
+      }]
+    }
+  }, {
+    "intent": "action.devices.EXECUTE",
+    "payload": {
+      "commands": [{
+        "devices": [{ "id": "111" }],
+        "execution": [{
+          "command": "action.devices.commands.BrightnessAbsolute",
+          "params": { "brightness": 42 }
+        }]
+      }]
+    }
+  }]
+}
+"#,
+    );
+    match response {
+        ActionResponse::Execute(response) => {
+            assert_eq!(2, response.payload.commands.len());
+            assert_eq!(Some(true), response.payload.commands[1].states.on);
+            assert_eq!(Some(42), response.payload.commands[1].states.brightness);
+        }
+        r => panic!("unexpected response: {:?}", r),
+    }
+}
+
+#[test]
+fn test_fulfill_unknown_intent() {
+    let response =
+        fulfill_json(r#"{"requestId": "1", "inputs": [{"intent": "action.devices.UNKNOWN"}]}"#);
+    let expected = ErrorResponse {
+        request_id: "1".to_string(),
+        payload: ErrorResponsePayload {
+            error_code: ErrorCode::ProtocolError,
+            debug_string: Some("unsupported intent: action.devices.UNKNOWN".to_string()),
+        },
+    };
+    assert_eq!(ActionResponse::Error(expected), response);
+}
+
+#[test]
+fn test_fulfill_device_errors() {
+    let response = fulfill_json(
+        r#"
+{
+  "requestId": "1",
+  "inputs": [{
+    "intent": "action.devices.EXECUTE",
+    "payload": {
+      "commands": [{
+        "devices": [{ "id": "111" }, { "id": "999" }],
+        "execution": [{
+          "command": "action.devices.commands.BrightnessAbsolute",
+          "params": { "brightness": 200 }
+        }]
+      }]
+    }
+  }]
+}
+"#,
+    );
+    match response {
+        ActionResponse::Execute(response) => {
+            let commands = response.payload.commands;
+            assert_eq!(2, commands.len());
+            assert_eq!(vec!["111".to_string()], commands[0].ids);
+            assert_eq!(Status::Error, commands[0].status);
+            assert_eq!(Some(ErrorCode::ValueOutOfRange), commands[0].error_code);
+            assert_eq!(vec!["999".to_string()], commands[1].ids);
+            assert_eq!(Status::Error, commands[1].status);
+            assert_eq!(Some(ErrorCode::DeviceNotFound), commands[1].error_code);
+        }
+        r => panic!("unexpected response: {:?}", r),
+    }
+}
+
+#[test]
+fn test_fulfill_undeclared_trait() {
+    let response = fulfill_json(
+        r#"
+{
+  "requestId": "1",
+  "inputs": [{
+    "intent": "action.devices.EXECUTE",
+    "payload": {
+      "commands": [{
+        "devices": [{ "id": "111" }],
+        "execution": [{
+          "command": "action.devices.commands.ThermostatSetMode",
+          "params": { "thermostatMode": "heat" }
+        }, {
+          "command": "action.devices.commands.ColorAbsolute",
+          "params": { "color": { "spectrumRGB": 16711680 } }
+        }]
+      }]
+    }
+  }]
+}
+"#,
+    );
+    match response {
+        ActionResponse::Execute(response) => {
+            let commands = response.payload.commands;
+            assert_eq!(2, commands.len());
+            assert_eq!(Some(ErrorCode::NotSupported), commands[0].error_code);
+            assert_eq!(Some(ErrorCode::NotSupported), commands[1].error_code);
+        }
+        r => panic!("unexpected response: {:?}", r),
+    }
+}
+
+#[test]
+fn test_fulfill_sync() {
+    let response =
+        fulfill_json(r#"{"requestId": "1", "inputs": [{"intent": "action.devices.SYNC"}]}"#);
+    match response {
+        ActionResponse::Sync(response) => {
+            assert_eq!("user", response.payload.agent_user_id);
+            assert_eq!(1, response.payload.devices.len());
+            assert_eq!("111", response.payload.devices[0].id);
+        }
+        r => panic!("unexpected response: {:?}", r),
+    }
+}
+
+#[test]
+fn test_fulfill_proxies() {
+    use proxy::mock_hub;
+
+    let (online, _) = mock_hub("p1", true);
+    let (offline, _) = mock_hub("p2", false);
+    let hub = build_hub(&format!(
+        r#"
+{{
+  "lights": [{{
+    "id": "111",
+    "name": "Bedroom lights",
+    "traits": ["OnOff"],
+    "pixels": [{{ "channel": 0, "offset": 0, "count": 16 }}]
+  }}],
+  "proxies": [{{ "url": {:?} }}, {{ "url": {:?} }}]
+}}
+"#,
+        online, offline
+    ));
+
+    let response = fulfill_with(
+        &hub,
+        r#"{"requestId": "1", "inputs": [{"intent": "action.devices.SYNC"}]}"#,
+    );
+    match response {
+        ActionResponse::Sync(response) => {
+            let ids: Vec<&str> = response.payload.devices.iter().map(|d| d.id.as_str()).collect();
+            assert_eq!(vec!["111", "p1", "p2"], ids);
+        }
+        r => panic!("unexpected response: {:?}", r),
+    }
+
+    let response = fulfill_with(
+        &hub,
+        r#"{"requestId": "2", "inputs": [{"intent": "action.devices.QUERY", "payload": {
+                "devices": [{"id": "111"}, {"id": "p1"}, {"id": "p2"}, {"id": "999"}]
+            }}]}"#,
+    );
+    match response {
+        ActionResponse::Query(response) => {
+            let devices = response.payload.devices;
+            assert_eq!(Some(Status::Success), devices["111"].status);
+            assert_eq!(Some(true), devices["p1"].on);
+            assert_eq!(Some(ErrorCode::DeviceOffline), devices["p2"].error_code);
+            assert_eq!(Some(ErrorCode::DeviceNotFound), devices["999"].error_code);
+        }
+        r => panic!("unexpected response: {:?}", r),
+    }
+
+    let response = fulfill_with(
+        &hub,
+        r#"{"requestId": "3", "inputs": [{"intent": "action.devices.EXECUTE", "payload": {
+                "commands": [{
+                  "devices": [{"id": "p1"}, {"id": "p2"}],
+                  "execution": [{
+                    "command": "action.devices.commands.OnOff", "params": {"on": false}
+                  }]
+                }]
+            }}]}"#,
+    );
+    match response {
+        ActionResponse::Execute(response) => {
+            let commands = response.payload.commands;
+            assert_eq!(2, commands.len());
+            assert_eq!(vec!["p1".to_string()], commands[0].ids);
+            assert_eq!(Status::Success, commands[0].status);
+            assert_eq!(vec!["p2".to_string()], commands[1].ids);
+            assert_eq!(Some(ErrorCode::DeviceOffline), commands[1].error_code);
+        }
+        r => panic!("unexpected response: {:?}", r),
+    }
+}
+
+#[test]
+fn test_fulfill_disconnect() {
+    use std::time::Duration;
+    use testing::mock_server;
+
+    let (url, requests) = mock_server(|_| Some("{}".to_string()));
+    let hub = build_hub(&format!(r#"{{ "homegraph": {{ "url": {:?} }} }}"#, url));
+    let dir = TempDir::new("fulfill");
+    let oauth = test_oauth(&dir);
+    let homegraph = hub.homegraph.clone().unwrap();
+    let token = oauth.link_user("user");
+    let other_token = oauth.link_user("other");
+    homegraph.add_user("user");
+    homegraph.add_user("other");
+
+    let request: ActionRequest = serde_json::from_str(
+        r#"{"requestId": "1", "inputs": [{"intent": "action.devices.DISCONNECT"}]}"#,
+    ).unwrap();
+    match fulfill(&hub, &oauth, "user", request) {
+        ActionResponse::Disconnect(_) => {}
+        r => panic!("unexpected response: {:?}", r),
+    }
+
+    assert_eq!(None, oauth.validate_token(&token));
+    assert_eq!(Some("other".to_string()), oauth.validate_token(&other_token));
+    assert_eq!(btreeset!{"other".to_string()}, oauth.linked_users());
+
+    // HomeGraph requests are no longer sent on behalf of the user.
+    homegraph.request_sync();
+    let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request.contains(r#""agentUserId":"other""#));
+    assert!(requests.recv_timeout(Duration::from_millis(500)).is_err());
+}
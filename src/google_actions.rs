@@ -1,6 +1,11 @@
 use std::collections::BTreeMap;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+use color;
+
+#[cfg(test)]
+use serde_json;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct SyncResponseDevice {
     pub id: String,
@@ -8,17 +13,17 @@ pub struct SyncResponseDevice {
     pub name: Name,
     pub traits: Vec<String>,
     pub will_report_state: bool,
-    #[serde(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub room_hint: Option<String>,
-    #[serde(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub structure_hint: Option<String>,
-    #[serde(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub device_info: Option<DeviceInfo>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub attributes: Option<SyncResponseDeviceAttributes>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct SyncResponseDeviceAttributes {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -27,25 +32,56 @@ pub struct SyncResponseDeviceAttributes {
     pub available_thermostat_modes: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub thermostat_temperature_unit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thermostat_temperature_range: Option<ThermostatTemperatureRange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color_temperature_range: Option<ColorTemperatureRange>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ColorTemperatureRange {
+    pub temperature_min_k: u64,
+    pub temperature_max_k: u64,
+}
+
+/// Setpoints accepted by a thermostat.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ThermostatTemperatureRange {
+    pub min_threshold_celsius: f32,
+    pub max_threshold_celsius: f32,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct Name {
-    #[serde(skip)]
-    pub default_name: Vec<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub default_names: Vec<String>,
     pub name: Option<String>,
-    #[serde(skip)]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pub nicknames: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct DeviceInfo {
-    pub manifacturer: String,
-    pub model: String,
-    pub hw_version: String,
-    pub sw_version: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hw_version: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sw_version: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -75,28 +111,113 @@ pub struct QueryResponsePayload {
     pub devices: BTreeMap<String, Params>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Command {
     pub devices: Vec<RequestDevice>,
     pub execution: Vec<Execution>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct RequestDevice {
     pub id: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Execution {
     pub command: String,
     pub params: Params,
 }
 
+/// Typed version of an `Execution`, with one variant per supported command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceCommand {
+    OnOff { on: bool },
+    BrightnessAbsolute { brightness: u8 },
+    ColorAbsolute { color: Color },
+    ThermostatTemperatureSetpoint { setpoint: f32 },
+    ThermostatTemperatureSetRange { low: f32, high: f32 },
+    ThermostatSetMode { mode: String },
+    ActivateScene { deactivate: bool },
+}
+
+impl DeviceCommand {
+    /// Parses an execution, failing with `notSupported` for unknown commands and with
+    /// `protocolError` if any of the parameters required by the command is missing.
+    pub fn from_execution(execution: &Execution) -> Result<DeviceCommand, ErrorCode> {
+        let p = &execution.params;
+        let command = match execution.command.as_ref() {
+            "action.devices.commands.OnOff" => p.on.map(|on| DeviceCommand::OnOff { on: on }),
+            "action.devices.commands.BrightnessAbsolute" => p.brightness
+                .map(|brightness| DeviceCommand::BrightnessAbsolute { brightness: brightness }),
+            "action.devices.commands.ColorAbsolute" => p.color
+                .clone()
+                .map(|color| DeviceCommand::ColorAbsolute { color: color }),
+            "action.devices.commands.ThermostatTemperatureSetpoint" => {
+                p.thermostat_temperature_setpoint.map(|setpoint| {
+                    DeviceCommand::ThermostatTemperatureSetpoint { setpoint: setpoint }
+                })
+            }
+            "action.devices.commands.ThermostatTemperatureSetRange" => match (
+                p.thermostat_temperature_setpoint_low,
+                p.thermostat_temperature_setpoint_high,
+            ) {
+                (Some(low), Some(high)) => Some(DeviceCommand::ThermostatTemperatureSetRange {
+                    low: low,
+                    high: high,
+                }),
+                _ => None,
+            },
+            "action.devices.commands.ThermostatSetMode" => p.thermostat_mode
+                .clone()
+                .map(|mode| DeviceCommand::ThermostatSetMode { mode: mode }),
+            "action.devices.commands.ActivateScene" => Some(DeviceCommand::ActivateScene {
+                deactivate: p.deactivate.unwrap_or(false),
+            }),
+            c => {
+                debug!("unsupported command: {:?}", c);
+                return Err(ErrorCode::NotSupported);
+            }
+        };
+        command.ok_or(ErrorCode::ProtocolError)
+    }
+
+    /// Traits, any of which a device must declare in SYNC in order to accept this command.
+    ///
+    /// Shades of white given only by name (e.g. "warm white") are accepted by lights supporting
+    /// either ColorTemperature or ColorSpectrum, as the latter can approximate them.
+    pub fn required_traits(&self) -> &'static [&'static str] {
+        match self {
+            &DeviceCommand::OnOff { .. } => &["action.devices.traits.OnOff"],
+            &DeviceCommand::BrightnessAbsolute { .. } => &["action.devices.traits.Brightness"],
+            &DeviceCommand::ColorAbsolute { ref color } => if color.temperature.is_some() {
+                &["action.devices.traits.ColorTemperature"]
+            } else if color
+                .name
+                .as_ref()
+                .map_or(false, |n| color::temperature_by_name(n).is_some())
+            {
+                &[
+                    "action.devices.traits.ColorTemperature",
+                    "action.devices.traits.ColorSpectrum",
+                ]
+            } else {
+                &["action.devices.traits.ColorSpectrum"]
+            },
+            &DeviceCommand::ThermostatTemperatureSetpoint { .. }
+            | &DeviceCommand::ThermostatTemperatureSetRange { .. }
+            | &DeviceCommand::ThermostatSetMode { .. } => {
+                &["action.devices.traits.TemperatureSetting"]
+            }
+            &DeviceCommand::ActivateScene { .. } => &["action.devices.traits.Scene"],
+        }
+    }
+}
+
 // TODO: Imple From and To for specific Device instances.
-#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Params {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -120,10 +241,16 @@ pub struct Params {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub thermostat_mode: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_thermostat_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub deactivate: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Color {
     #[serde(default)]
@@ -136,6 +263,17 @@ pub struct Color {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "spectrumRGB")]
     pub spectrum_rgb: Option<u64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "spectrumHsv")]
+    pub spectrum_hsv: Option<SpectrumHsv>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpectrumHsv {
+    pub hue: f32,
+    pub saturation: f32,
+    pub value: f32,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -148,7 +286,7 @@ pub struct ExecuteResponse {
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ExecuteResponsePayload {
-    pub error_code: Option<String>,
+    pub error_code: Option<ErrorCode>,
     pub debug_string: Option<String>,
     pub commands: Vec<ExecuteResponseCommand>,
 }
@@ -157,10 +295,81 @@ pub struct ExecuteResponsePayload {
 #[serde(rename_all = "camelCase")]
 pub struct ExecuteResponseCommand {
     pub ids: Vec<String>,
-    pub status: String,
+    pub status: Status,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<ErrorCode>,
     pub states: Params,
 }
 
+/// Result of a QUERY or EXECUTE for a device.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Status {
+    Success,
+    Pending,
+    Offline,
+    Exceptions,
+    Error,
+}
+
+/// Reason of a failed QUERY or EXECUTE, either for a single device or for the whole request.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    AuthExpired,
+    AuthFailure,
+    DeviceOffline,
+    Timeout,
+    DeviceTurnedOff,
+    DeviceNotFound,
+    ValueOutOfRange,
+    RangeTooClose,
+    NotSupported,
+    ProtocolError,
+    UnknownError,
+}
+
+impl ErrorCode {
+    /// Status reported alongside this error code.
+    pub fn status(&self) -> Status {
+        match self {
+            &ErrorCode::DeviceOffline => Status::Offline,
+            _ => Status::Error,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ActionResponse {
+    Sync(SyncResponse),
+    Query(QueryResponse),
+    Execute(ExecuteResponse),
+    Error(ErrorResponse),
+    Disconnect(DisconnectResponse),
+}
+
+/// Response to a request that could not be handled as a whole.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    pub request_id: String,
+    pub payload: ErrorResponsePayload,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponsePayload {
+    pub error_code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug_string: Option<String>,
+}
+
+/// Response to the DISCONNECT intent, which is an empty object.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DisconnectResponse {}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ActionRequest {
@@ -324,7 +533,10 @@ fn test_execute_request() {
                                         thermostat_temperature_setpoint_low: None,
                                         thermostat_temperature_setpoint_high: None,
                                         thermostat_mode: None,
+                                        active_thermostat_mode: None,
                                         deactivate: None,
+                                        status: None,
+                                        error_code: None,
                                     },
                                 },
                             ],
@@ -336,3 +548,115 @@ fn test_execute_request() {
     };
     assert_eq!(expected_req, parsed_req);
 }
+
+#[test]
+fn test_disconnect_response() {
+    let response = DisconnectResponse {};
+    assert_eq!("{}", serde_json::to_string(&response).unwrap());
+}
+
+#[test]
+fn test_query_response_error() {
+    let response = QueryResponse {
+        request_id: "1".to_string(),
+        payload: QueryResponsePayload {
+            devices: btreemap!{
+                "123".to_string() => Params {
+                    status: Some(Status::Error),
+                    error_code: Some(ErrorCode::DeviceNotFound),
+                    ..Params::default()
+                },
+            },
+        },
+    };
+    assert_eq!(
+        r#"{"requestId":"1","payload":{"devices":{"123":{"status":"ERROR","errorCode":"deviceNotFound"}}}}"#,
+        serde_json::to_string(&response).unwrap()
+    );
+}
+
+#[test]
+fn test_device_command() {
+    let execution = Execution {
+        command: "action.devices.commands.ThermostatTemperatureSetRange".to_string(),
+        params: Params {
+            thermostat_temperature_setpoint_low: Some(18.0),
+            thermostat_temperature_setpoint_high: Some(22.0),
+            ..Params::default()
+        },
+    };
+    assert_eq!(
+        Ok(DeviceCommand::ThermostatTemperatureSetRange {
+            low: 18.0,
+            high: 22.0,
+        }),
+        DeviceCommand::from_execution(&execution)
+    );
+
+    let execution = Execution {
+        command: "action.devices.commands.OnOff".to_string(),
+        params: Params::default(),
+    };
+    assert_eq!(
+        Err(ErrorCode::ProtocolError),
+        DeviceCommand::from_execution(&execution)
+    );
+
+    let execution = Execution {
+        command: "action.devices.commands.Unknown".to_string(),
+        params: Params::default(),
+    };
+    assert_eq!(
+        Err(ErrorCode::NotSupported),
+        DeviceCommand::from_execution(&execution)
+    );
+}
+
+#[test]
+fn test_required_traits() {
+    let color = |name: &str| DeviceCommand::ColorAbsolute {
+        color: Color {
+            name: Some(name.to_string()),
+            temperature: None,
+            spectrum_rgb: None,
+            spectrum_hsv: None,
+        },
+    };
+    assert_eq!(
+        &[
+            "action.devices.traits.ColorTemperature",
+            "action.devices.traits.ColorSpectrum",
+        ],
+        color("Warm White").required_traits()
+    );
+    assert_eq!(
+        &["action.devices.traits.ColorSpectrum"],
+        color("red").required_traits()
+    );
+}
+
+#[test]
+fn test_sync_response_device() {
+    let device = SyncResponseDevice {
+        id: "222".to_string(),
+        type_: "action.devices.types.LIGHT".to_string(),
+        name: Name {
+            default_names: vec!["Mote".to_string()],
+            name: Some("Kitchen lights".to_string()),
+            nicknames: vec!["worktop lights".to_string()],
+        },
+        traits: vec!["action.devices.traits.OnOff".to_string()],
+        will_report_state: false,
+        room_hint: Some("kitchen".to_string()),
+        structure_hint: None,
+        device_info: Some(DeviceInfo {
+            manufacturer: Some("Pimoroni".to_string()),
+            model: None,
+            hw_version: None,
+            sw_version: None,
+        }),
+        attributes: None,
+    };
+    let expected = r#"{"id":"222","type":"action.devices.types.LIGHT","name":{"defaultNames":["Mote"],"name":"Kitchen lights","nicknames":["worktop lights"]},"traits":["action.devices.traits.OnOff"],"willReportState":false,"roomHint":"kitchen","deviceInfo":{"manufacturer":"Pimoroni"}}"#;
+    assert_eq!(expected, serde_json::to_string(&device).unwrap());
+}
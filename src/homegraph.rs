@@ -0,0 +1,317 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use rand::{thread_rng, Rng};
+use reqwest;
+use reqwest::header::{Authorization, Bearer};
+use ring::digest;
+use serde::Serialize;
+use serde_json;
+use url::Url;
+
+use google_actions::{Params, SyncResponseDevice};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HomeGraphConfig {
+    /// Base URL of the HomeGraph API.
+    #[serde(default = "default_url")]
+    pub url: String,
+    /// API key appended to every request, if any.
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// File containing an OAuth access token for the HomeGraph API, if any.
+    ///
+    /// The file is read again before every request, so that the token may be refreshed by an
+    /// external process.
+    #[serde(default)]
+    pub access_token_file: Option<String>,
+    /// File where a hash of the devices last reported in SYNC is saved, so that a new SYNC is only
+    /// requested on startup if they changed.
+    #[serde(default = "default_sync_hash_store")]
+    pub sync_hash_store: String,
+}
+
+fn default_url() -> String {
+    "https://homegraph.googleapis.com".to_string()
+}
+
+fn default_sync_hash_store() -> String {
+    "sync_hash.txt".to_string()
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ReportStateRequest {
+    request_id: String,
+    agent_user_id: String,
+    payload: ReportStatePayload,
+}
+
+#[derive(Serialize, Debug)]
+struct ReportStatePayload {
+    devices: ReportStateDevices,
+}
+
+#[derive(Serialize, Debug)]
+struct ReportStateDevices {
+    states: BTreeMap<String, Params>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RequestSyncRequest {
+    agent_user_id: String,
+}
+
+enum Message {
+    ReportState(String, Params),
+    RequestSync,
+}
+
+/// Client for the HomeGraph API, used to push state changes to Google instead of waiting for it to
+/// poll via QUERY.
+///
+/// Requests are sent from a background thread on behalf of every linked user, so that callers
+/// (which usually hold a device lock) never block on the network.
+pub struct HomeGraph {
+    users: Arc<Mutex<BTreeSet<String>>>,
+    sender: Mutex<mpsc::Sender<Message>>,
+    sync_hash_store: String,
+}
+
+impl HomeGraph {
+    pub fn new(config: HomeGraphConfig) -> HomeGraph {
+        let users = Arc::new(Mutex::new(BTreeSet::new()));
+        let (sender, receiver) = mpsc::channel();
+
+        let sync_hash_store = config.sync_hash_store.clone();
+        let worker_users = users.clone();
+        thread::spawn(move || {
+            let client = reqwest::Client::new();
+            for message in receiver {
+                let users = worker_users.lock().unwrap().clone();
+                for user in users {
+                    let result = match message {
+                        Message::ReportState(ref id, ref state) => post(
+                            &client,
+                            &config,
+                            "reportStateAndNotification",
+                            &ReportStateRequest {
+                                request_id: thread_rng().gen_ascii_chars().take(16).collect(),
+                                agent_user_id: user.clone(),
+                                payload: ReportStatePayload {
+                                    devices: ReportStateDevices {
+                                        states: btreemap!{ id.clone() => state.clone() },
+                                    },
+                                },
+                            },
+                        ),
+                        Message::RequestSync => post(
+                            &client,
+                            &config,
+                            "requestSync",
+                            &RequestSyncRequest {
+                                agent_user_id: user.clone(),
+                            },
+                        ),
+                    };
+                    if let Err(err) = result {
+                        error!("homegraph request for user {:?} failed: {}", user, err);
+                    }
+                }
+            }
+        });
+
+        HomeGraph {
+            users: users,
+            sender: Mutex::new(sender),
+            sync_hash_store: sync_hash_store,
+        }
+    }
+
+    /// Starts reporting on behalf of the given user.
+    pub fn add_user(&self, user_id: &str) {
+        self.users.lock().unwrap().insert(user_id.to_string());
+    }
+
+    /// Stops reporting on behalf of the given user, e.g. after they unlinked their account.
+    pub fn remove_user(&self, user_id: &str) {
+        self.users.lock().unwrap().remove(user_id);
+    }
+
+    /// Reports the current state of a device.
+    pub fn report_state(&self, device_id: &str, state: Params) {
+        self.send(Message::ReportState(device_id.to_string(), state));
+    }
+
+    /// Asks Google to send a new SYNC request, e.g. because the set of devices changed.
+    pub fn request_sync(&self) {
+        self.send(Message::RequestSync);
+    }
+
+    /// Asks Google to send a new SYNC request if the given devices differ from the ones passed
+    /// the last time this was called, possibly before a restart.
+    pub fn request_sync_if_changed(&self, devices: &[SyncResponseDevice]) {
+        let hash = sync_hash(devices);
+        let mut saved = String::new();
+        let _ = File::open(&self.sync_hash_store).and_then(|mut f| f.read_to_string(&mut saved));
+        if saved.trim() == hash {
+            debug!("devices unchanged, not requesting sync");
+            return;
+        }
+        self.request_sync();
+
+        let tmp_path = format!("{}.tmp", self.sync_hash_store);
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| writeln!(file, "{}", hash))
+            .and_then(|_| fs::rename(&tmp_path, &self.sync_hash_store));
+        if let Err(err) = result {
+            error!(
+                "could not save sync hash to {}: {}",
+                self.sync_hash_store, err
+            );
+        }
+    }
+
+    fn send(&self, message: Message) {
+        if let Err(err) = self.sender.lock().unwrap().send(message) {
+            error!("could not send homegraph message: {:?}", err);
+        }
+    }
+}
+
+/// Returns a hex encoded SHA-256 hash of the SYNC description of the given devices.
+fn sync_hash(devices: &[SyncResponseDevice]) -> String {
+    let json = serde_json::to_vec(devices).unwrap();
+    digest::digest(&digest::SHA256, &json)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn post<T: Serialize>(
+    client: &reqwest::Client,
+    config: &HomeGraphConfig,
+    method: &str,
+    body: &T,
+) -> Result<(), String> {
+    let mut url = Url::parse(&format!("{}/v1/devices:{}", config.url, method))
+        .map_err(|e| format!("invalid homegraph url {:?}: {}", config.url, e))?;
+    if let Some(ref key) = config.api_key {
+        url.query_pairs_mut().append_pair("key", key);
+    }
+
+    let mut builder = client.post(url.clone());
+    builder.json(body);
+    if let Some(ref path) = config.access_token_file {
+        let mut token = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut token))
+            .map_err(|e| format!("could not read {}: {}", path, e))?;
+        builder.header(Authorization(Bearer {
+            token: token.trim().to_string(),
+        }));
+    }
+
+    let res = builder
+        .send()
+        .map_err(|e| format!("could not send request to {}: {}", url, e))?;
+    debug!("homegraph response: {:?}", res);
+    if !res.status().is_success() {
+        return Err(format!("{} returned {}", url, res.status()));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_report_state() {
+    use std::time::Duration;
+    use testing::mock_server;
+
+    let (url, requests) = mock_server(|_| Some("{}".to_string()));
+    let homegraph = HomeGraph::new(HomeGraphConfig {
+        url: url,
+        api_key: Some("key".to_string()),
+        access_token_file: None,
+        sync_hash_store: default_sync_hash_store(),
+    });
+    homegraph.add_user("user");
+    homegraph.report_state(
+        "111",
+        Params {
+            on: Some(true),
+            ..Params::default()
+        },
+    );
+
+    let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request.starts_with("POST /v1/devices:reportStateAndNotification?key=key "));
+    assert!(request.contains(r#""agentUserId":"user""#));
+    assert!(request.contains(r#""states":{"111":{"on":true}}"#));
+}
+
+#[test]
+fn test_request_sync() {
+    use std::time::Duration;
+    use testing::mock_server;
+
+    let (url, requests) = mock_server(|_| Some("{}".to_string()));
+    let homegraph = HomeGraph::new(HomeGraphConfig {
+        url: url,
+        api_key: None,
+        access_token_file: None,
+        sync_hash_store: default_sync_hash_store(),
+    });
+    homegraph.add_user("user");
+    homegraph.request_sync();
+
+    let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request.starts_with("POST /v1/devices:requestSync "));
+    assert!(request.contains(r#"{"agentUserId":"user"}"#));
+}
+
+#[test]
+fn test_request_sync_if_changed() {
+    use std::time::Duration;
+    use testing::{mock_server, TempDir};
+
+    let (url, requests) = mock_server(|_| Some("{}".to_string()));
+    let dir = TempDir::new("homegraph");
+    let config = HomeGraphConfig {
+        url: url,
+        api_key: None,
+        access_token_file: None,
+        sync_hash_store: dir.file("sync_hash.txt"),
+    };
+    let devices = |name: &str| -> Vec<SyncResponseDevice> {
+        serde_json::from_str(&format!(
+            r#"[{{
+                "id": "111", "type": "action.devices.types.LIGHT", "name": {{"name": {:?}}},
+                "traits": ["action.devices.traits.OnOff"], "willReportState": true
+            }}]"#,
+            name
+        )).unwrap()
+    };
+
+    let homegraph = HomeGraph::new(config.clone());
+    homegraph.add_user("user");
+    homegraph.request_sync_if_changed(&devices("Lamp"));
+    let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request.starts_with("POST /v1/devices:requestSync "));
+
+    // The hash survives restarts.
+    let homegraph = HomeGraph::new(config.clone());
+    homegraph.add_user("user");
+    homegraph.request_sync_if_changed(&devices("Lamp"));
+    assert!(requests.recv_timeout(Duration::from_millis(500)).is_err());
+    homegraph.request_sync_if_changed(&devices("Porch"));
+    let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request.starts_with("POST /v1/devices:requestSync "));
+    homegraph.request_sync_if_changed(&devices("Porch"));
+    assert!(requests.recv_timeout(Duration::from_millis(500)).is_err());
+}
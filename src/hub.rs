@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use device::Device;
+use homegraph::HomeGraph;
+use light::Light;
+use proxy::Proxy;
+use schedule::ScheduleStore;
+use thermostat::Thermostat;
+
+pub struct Hub {
+    pub devices: Vec<Arc<Mutex<Device>>>,
+    pub lights: Vec<Arc<Mutex<Light>>>,
+    pub thermostats: Vec<Arc<Mutex<Thermostat>>>,
+    pub proxies: Vec<Proxy>,
+    pub homegraph: Option<Arc<HomeGraph>>,
+    pub schedule_store: ScheduleStore,
+    /// Time at which the proxies were last synced to find unknown devices, if ever.
+    pub proxies_refreshed: Mutex<Option<Instant>>,
+}
+
+/// Minimum interval between syncs of the proxies to find unknown devices, so that requests for
+/// devices that no longer exist do not each cost a sync of every proxy.
+const PROXY_REFRESH_INTERVAL_SECS: u64 = 60;
+
+impl Hub {
+    /// Returns the local device with the given id, if any.
+    pub fn device(&self, id: &str) -> Option<&Arc<Mutex<Device>>> {
+        self.devices
+            .iter()
+            .find(|d| d.lock().unwrap().id() == id)
+    }
+
+    /// Returns the local thermostat with the given id, if any.
+    pub fn thermostat(&self, id: &str) -> Option<&Arc<Mutex<Thermostat>>> {
+        self.thermostats
+            .iter()
+            .find(|t| t.lock().unwrap().id == id)
+    }
+
+    /// Returns the index of the proxy owning each of the given devices, for the devices owned by
+    /// a proxy.
+    ///
+    /// If some device is not known to be owned by any proxy (e.g. because QUERY or EXECUTE are
+    /// received before any SYNC since the server started), all the proxies are synced again, at
+    /// most once per call and once every `PROXY_REFRESH_INTERVAL_SECS`. If this changes the set of
+    /// devices of any proxy, Google is asked to SYNC again.
+    pub fn proxy_owners(&self, request_id: &str, ids: &[String]) -> BTreeMap<String, usize> {
+        let owner = |id: &str| self.proxies.iter().position(|p| p.owns(id));
+        if ids.iter().any(|id| owner(id).is_none()) && self.may_refresh_proxies(Instant::now()) {
+            let mut changed = false;
+            for proxy in &self.proxies {
+                match proxy.refresh(request_id) {
+                    Ok(c) => changed |= c,
+                    Err(err) => error!("could not sync proxy {}: {}", proxy.url, err),
+                }
+            }
+            if changed {
+                if let Some(ref homegraph) = self.homegraph {
+                    homegraph.request_sync();
+                }
+            }
+        }
+        ids.iter()
+            .filter_map(|id| owner(id).map(|i| (id.clone(), i)))
+            .collect()
+    }
+
+    /// Returns whether the proxies may be synced again at the given time, recording it if so.
+    fn may_refresh_proxies(&self, now: Instant) -> bool {
+        if self.proxies.is_empty() {
+            return false;
+        }
+        let mut refreshed = self.proxies_refreshed.lock().unwrap();
+        let interval = Duration::from_secs(PROXY_REFRESH_INTERVAL_SECS);
+        if refreshed.map_or(false, |t| now.duration_since(t) < interval) {
+            return false;
+        }
+        *refreshed = Some(now);
+        true
+    }
+}
+
+#[test]
+fn test_proxy_owners() {
+    use proxy::mock_hub;
+    use testing::build_hub;
+
+    let (url, requests) = mock_hub("p1", true);
+    let hub = build_hub(&format!(r#"{{ "proxies": [{{ "url": {:?} }}] }}"#, url));
+    let ids = vec!["p1".to_string(), "p3".to_string(), "p4".to_string()];
+    let sync = || requests.recv_timeout(Duration::from_millis(500)).is_ok();
+
+    // The proxies are synced once for all the unknown devices.
+    assert_eq!(btreemap!{ "p1".to_string() => 0 }, hub.proxy_owners("1", &ids));
+    assert!(sync());
+    assert!(!sync());
+    // And not again until the interval elapsed.
+    assert_eq!(btreemap!{ "p1".to_string() => 0 }, hub.proxy_owners("2", &ids));
+    assert!(!sync());
+    *hub.proxies_refreshed.lock().unwrap() = None;
+    hub.proxy_owners("3", &ids);
+    assert!(sync());
+}
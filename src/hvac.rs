@@ -0,0 +1,439 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::string::ToString;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use thermostat::{ThermostatMode, ThermostatStatus};
+
+/// What the heating/cooling equipment is currently doing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Activity {
+    Idle,
+    Heating,
+    Cooling,
+}
+
+impl Default for Activity {
+    fn default() -> Activity {
+        Activity::Idle
+    }
+}
+
+/// Value of `activeThermostatMode` reported in QUERY.
+impl ToString for Activity {
+    fn to_string(&self) -> String {
+        match self {
+            &Activity::Idle => "none".to_string(),
+            &Activity::Heating => "heat".to_string(),
+            &Activity::Cooling => "cool".to_string(),
+        }
+    }
+}
+
+/// Equipment driven by a thermostat.
+pub trait HvacOutput: Send + Sync {
+    fn can_heat(&self) -> bool;
+    fn can_cool(&self) -> bool;
+    fn set(&mut self, activity: Activity) -> Result<(), String>;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OutputConfig {
+    /// Keeps the activity in memory only, for testing without any hardware attached.
+    Mock,
+    /// Drives relays through the sysfs GPIO interface.
+    Gpio {
+        #[serde(default)]
+        heat_pin: Option<u32>,
+        #[serde(default)]
+        cool_pin: Option<u32>,
+        /// Whether the relays are energized by driving the pins low.
+        #[serde(default)]
+        active_low: bool,
+        #[serde(default = "default_gpio_path")]
+        path: String,
+    },
+}
+
+fn default_gpio_path() -> String {
+    "/sys/class/gpio".to_string()
+}
+
+impl Default for OutputConfig {
+    fn default() -> OutputConfig {
+        OutputConfig::Mock
+    }
+}
+
+impl OutputConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            &OutputConfig::Mock => Ok(()),
+            &OutputConfig::Gpio {
+                heat_pin: None,
+                cool_pin: None,
+                ..
+            } => Err("gpio output needs a heat_pin or a cool_pin".to_string()),
+            &OutputConfig::Gpio { .. } => Ok(()),
+        }
+    }
+
+    /// Builds the output, turning off any equipment left running, e.g. by a crash.
+    pub fn build(&self) -> Result<Box<HvacOutput>, String> {
+        let mut output: Box<HvacOutput> = match self {
+            &OutputConfig::Mock => Box::new(MockOutput::default()),
+            &OutputConfig::Gpio {
+                heat_pin,
+                cool_pin,
+                active_low,
+                ref path,
+            } => Box::new(GpioOutput {
+                heat: heat_pin.map(|pin| GpioPin::new(Path::new(path), pin, active_low)),
+                cool: cool_pin.map(|pin| GpioPin::new(Path::new(path), pin, active_low)),
+            }),
+        };
+        output.set(Activity::Idle)?;
+        Ok(output)
+    }
+}
+
+/// Output that only records the last activity, shared so that tests can inspect it.
+#[derive(Default, Clone)]
+pub struct MockOutput {
+    pub activity: Arc<Mutex<Activity>>,
+}
+
+impl HvacOutput for MockOutput {
+    fn can_heat(&self) -> bool {
+        true
+    }
+
+    fn can_cool(&self) -> bool {
+        true
+    }
+
+    fn set(&mut self, activity: Activity) -> Result<(), String> {
+        debug!("mock hvac output: {:?}", activity);
+        *self.activity.lock().unwrap() = activity;
+        Ok(())
+    }
+}
+
+/// Output driving one relay for heating and/or one for cooling through sysfs GPIO.
+pub struct GpioOutput {
+    heat: Option<GpioPin>,
+    cool: Option<GpioPin>,
+}
+
+impl HvacOutput for GpioOutput {
+    fn can_heat(&self) -> bool {
+        self.heat.is_some()
+    }
+
+    fn can_cool(&self) -> bool {
+        self.cool.is_some()
+    }
+
+    fn set(&mut self, activity: Activity) -> Result<(), String> {
+        // Turn off first, so that both relays are never energized at the same time.
+        if let Some(ref pin) = self.heat {
+            if activity != Activity::Heating {
+                pin.write(false)?;
+            }
+        }
+        if let Some(ref pin) = self.cool {
+            if activity != Activity::Cooling {
+                pin.write(false)?;
+            }
+        }
+        match (activity, &self.heat, &self.cool) {
+            (Activity::Heating, &Some(ref pin), _) => pin.write(true),
+            (Activity::Cooling, _, &Some(ref pin)) => pin.write(true),
+            (Activity::Idle, _, _) => Ok(()),
+            _ => Err(format!("output cannot handle {:?}", activity)),
+        }
+    }
+}
+
+struct GpioPin {
+    base: PathBuf,
+    pin: u32,
+    active_low: bool,
+}
+
+impl GpioPin {
+    fn new(base: &Path, pin: u32, active_low: bool) -> GpioPin {
+        GpioPin {
+            base: base.to_path_buf(),
+            pin: pin,
+            active_low: active_low,
+        }
+    }
+
+    /// Exports the pin as an output, unless it was already exported.
+    fn export(&self) -> Result<PathBuf, String> {
+        let dir = self.base.join(format!("gpio{}", self.pin));
+        if !dir.exists() {
+            write_file(&self.base.join("export"), &self.pin.to_string())?;
+            write_file(&dir.join("direction"), "out")?;
+        }
+        Ok(dir.join("value"))
+    }
+
+    fn write(&self, on: bool) -> Result<(), String> {
+        let value = self.export()?;
+        write_file(&value, if on != self.active_low { "1" } else { "0" })
+    }
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), String> {
+    File::create(path)
+        .and_then(|mut f| f.write_all(contents.as_bytes()))
+        .map_err(|e| format!("could not write {}: {}", path.display(), e))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ControlConfig {
+    /// Distance from the target temperature (in degrees Celsius) that the ambient temperature must
+    /// cross before the equipment is turned on or off.
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: f32,
+    /// Minimum time the equipment stays on once turned on.
+    #[serde(default = "default_min_secs")]
+    pub min_on_secs: u64,
+    /// Minimum time the equipment stays off once turned off.
+    #[serde(default = "default_min_secs")]
+    pub min_off_secs: u64,
+}
+
+fn default_hysteresis() -> f32 {
+    0.5
+}
+
+fn default_min_secs() -> u64 {
+    60
+}
+
+impl Default for ControlConfig {
+    fn default() -> ControlConfig {
+        ControlConfig {
+            hysteresis: default_hysteresis(),
+            min_on_secs: default_min_secs(),
+            min_off_secs: default_min_secs(),
+        }
+    }
+}
+
+/// Bang-bang controller deciding whether to heat or cool based on the thermostat status.
+pub struct Controller {
+    config: ControlConfig,
+    activity: Activity,
+    last_change: Option<Instant>,
+}
+
+impl Controller {
+    pub fn new(config: ControlConfig) -> Controller {
+        Controller {
+            config: config,
+            activity: Activity::Idle,
+            last_change: None,
+        }
+    }
+
+    /// Returns the activity the equipment should switch to at the given time, which only takes
+    /// effect once passed to `commit`.
+    ///
+    /// In `heat` and `cool` modes the ambient temperature is compared to the setpoint, in
+    /// `heatcool` mode to the low and high ends of the range, and in `on` mode the setpoint is
+    /// held using whatever the equipment is capable of.
+    pub fn decide(
+        &self,
+        status: &ThermostatStatus,
+        can_heat: bool,
+        can_cool: bool,
+        now: Instant,
+    ) -> Activity {
+        let (heat_target, cool_target) = match status.mode {
+            ThermostatMode::Off => (None, None),
+            ThermostatMode::Heat => (Some(status.temperature_setpoint), None),
+            ThermostatMode::Cool => (None, Some(status.temperature_setpoint)),
+            ThermostatMode::On => (
+                Some(status.temperature_setpoint),
+                Some(status.temperature_setpoint),
+            ),
+            ThermostatMode::Heatcool => (
+                Some(status.temperature_setpoint_low),
+                Some(status.temperature_setpoint_high),
+            ),
+        };
+
+        let h = self.config.hysteresis;
+        let ambient = status.temperature_ambient;
+        let heat = match heat_target {
+            Some(target) if can_heat => if self.activity == Activity::Heating {
+                ambient < target + h
+            } else {
+                ambient < target - h
+            },
+            _ => false,
+        };
+        let cool = match cool_target {
+            Some(target) if can_cool => if self.activity == Activity::Cooling {
+                ambient > target - h
+            } else {
+                ambient > target + h
+            },
+            _ => false,
+        };
+        let desired = match (heat, cool) {
+            (true, _) => Activity::Heating,
+            (false, true) => Activity::Cooling,
+            (false, false) => Activity::Idle,
+        };
+
+        if desired == self.activity {
+            return self.activity;
+        }
+        let min = Duration::from_secs(if self.activity == Activity::Idle {
+            self.config.min_off_secs
+        } else {
+            self.config.min_on_secs
+        });
+        if let Some(last_change) = self.last_change {
+            if now.duration_since(last_change) < min {
+                return self.activity;
+            }
+        }
+        // Never switch directly between heating and cooling.
+        if self.activity != Activity::Idle {
+            Activity::Idle
+        } else {
+            desired
+        }
+    }
+
+    /// Records that the equipment switched to the given activity.
+    pub fn commit(&mut self, activity: Activity, now: Instant) {
+        if activity != self.activity {
+            self.activity = activity;
+            self.last_change = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_status(mode: ThermostatMode, ambient: f32) -> ThermostatStatus {
+    ThermostatStatus {
+        online: true,
+        mode: mode,
+        temperature_setpoint: 20.0,
+        temperature_ambient: ambient,
+        temperature_setpoint_low: 18.0,
+        temperature_setpoint_high: 24.0,
+        humidity_ambient: 50.0,
+        activity: Activity::Idle,
+    }
+}
+
+/// Decides on the activity and commits it, as if the output never failed.
+#[cfg(test)]
+fn update(
+    controller: &mut Controller,
+    status: &ThermostatStatus,
+    can_cool: bool,
+    now: Instant,
+) -> Activity {
+    let activity = controller.decide(status, true, can_cool, now);
+    controller.commit(activity, now);
+    activity
+}
+
+#[test]
+fn test_controller_hysteresis() {
+    let mut controller = Controller::new(ControlConfig {
+        hysteresis: 0.5,
+        min_on_secs: 0,
+        min_off_secs: 0,
+    });
+    let now = Instant::now();
+    let mut step = |mode, ambient| {
+        update(&mut controller, &test_status(mode, ambient), true, now)
+    };
+
+    assert_eq!(Activity::Idle, step(ThermostatMode::Heat, 19.6));
+    assert_eq!(Activity::Heating, step(ThermostatMode::Heat, 19.4));
+    assert_eq!(Activity::Heating, step(ThermostatMode::Heat, 20.4));
+    assert_eq!(Activity::Idle, step(ThermostatMode::Heat, 20.6));
+    assert_eq!(Activity::Idle, step(ThermostatMode::Off, 10.0));
+
+    assert_eq!(Activity::Idle, step(ThermostatMode::Heatcool, 20.0));
+    assert_eq!(Activity::Cooling, step(ThermostatMode::Heatcool, 24.6));
+    assert_eq!(Activity::Idle, step(ThermostatMode::Heatcool, 23.4));
+    assert_eq!(Activity::Heating, step(ThermostatMode::Heatcool, 17.4));
+}
+
+#[test]
+fn test_controller_min_times() {
+    let mut controller = Controller::new(ControlConfig {
+        hysteresis: 0.0,
+        min_on_secs: 60,
+        min_off_secs: 30,
+    });
+    let t0 = Instant::now();
+    let cold = test_status(ThermostatMode::On, 10.0);
+    let hot = test_status(ThermostatMode::On, 30.0);
+
+    assert_eq!(Activity::Heating, update(&mut controller, &cold, true, t0));
+    // Stays on for at least 60s, then goes through idle before cooling.
+    let t = t0 + Duration::from_secs(59);
+    assert_eq!(Activity::Heating, update(&mut controller, &hot, true, t));
+    let t = t0 + Duration::from_secs(60);
+    assert_eq!(Activity::Idle, update(&mut controller, &hot, true, t));
+    let t = t0 + Duration::from_secs(89);
+    assert_eq!(Activity::Idle, update(&mut controller, &hot, true, t));
+    let t = t0 + Duration::from_secs(90);
+    assert_eq!(Activity::Cooling, update(&mut controller, &hot, true, t));
+    // Equipment that cannot cool is never asked to.
+    let mut controller = Controller::new(ControlConfig::default());
+    assert_eq!(Activity::Idle, update(&mut controller, &hot, false, t0));
+}
+
+#[test]
+fn test_gpio_output() {
+    use std::fs;
+    use std::io::Read;
+    use testing::TempDir;
+
+    let dir = TempDir::new("gpio");
+    let base = dir.path();
+    fs::create_dir_all(base.join("gpio17")).unwrap();
+    fs::create_dir_all(base.join("gpio27")).unwrap();
+    let read = |pin: u32| {
+        let mut s = String::new();
+        File::open(base.join(format!("gpio{}/value", pin)))
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        s
+    };
+
+    let mut output = OutputConfig::Gpio {
+        heat_pin: Some(17),
+        cool_pin: Some(27),
+        active_low: true,
+        path: base.to_str().unwrap().to_string(),
+    }.build()
+        .unwrap();
+    // Equipment is turned off on startup.
+    assert_eq!("1", read(17));
+    assert_eq!("1", read(27));
+    output.set(Activity::Heating).unwrap();
+    assert_eq!("0", read(17));
+    assert_eq!("1", read(27));
+    output.set(Activity::Idle).unwrap();
+    assert_eq!("1", read(17));
+    assert_eq!("1", read(27));
+}
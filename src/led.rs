@@ -0,0 +1,399 @@
+extern crate rgb;
+
+use std::io;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use color::BLACK;
+use mote;
+
+/// Device displaying the pixels rendered for the lights.
+pub trait LedOutput {
+    /// Displays a frame, given as the pixels of each channel.
+    fn write(&mut self, channels: &[Vec<rgb::RGB8>]) -> Result<(), String>;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OutputConfig {
+    /// Pimoroni Mote, on the serial port given by `--mote_dev`.
+    Mote,
+    /// Draws the pixels on the terminal, for running without any hardware attached.
+    Terminal,
+    /// Open Pixel Control server, such as fadecandy.
+    Opc {
+        /// `host:port` of the server.
+        address: String,
+        /// OPC channel of each output channel; output channels sent to the same OPC channel are
+        /// concatenated. By default, all of them are sent to channel 0.
+        #[serde(default)]
+        channels: Vec<u8>,
+        /// Delay before reconnecting after the connection failed.
+        #[serde(default = "default_reconnect_secs")]
+        reconnect_secs: u64,
+    },
+}
+
+fn default_reconnect_secs() -> u64 {
+    5
+}
+
+impl OutputConfig {
+    /// Checks the output against the number of pixels on each output channel.
+    pub fn validate(&self, channels: &[usize]) -> Result<(), String> {
+        match self {
+            &OutputConfig::Mote => {
+                if channels.len() > MOTE_CHANNELS
+                    || channels.iter().any(|&count| count > MOTE_PIXELS)
+                {
+                    return Err(format!(
+                        "mote output supports at most {} channels of {} pixels",
+                        MOTE_CHANNELS, MOTE_PIXELS
+                    ));
+                }
+                Ok(())
+            }
+            &OutputConfig::Terminal => Ok(()),
+            &OutputConfig::Opc {
+                channels: ref opc_channels,
+                ..
+            } => {
+                if !opc_channels.is_empty() && opc_channels.len() != channels.len() {
+                    return Err(format!(
+                        "opc output must map all {} channels",
+                        channels.len()
+                    ));
+                }
+                let mut totals: Vec<(u8, usize)> = vec![];
+                for (i, &count) in channels.iter().enumerate() {
+                    let opc_channel = opc_channels.get(i).cloned().unwrap_or(0);
+                    match totals.iter().position(|&(c, _)| c == opc_channel) {
+                        Some(j) => totals[j].1 += count,
+                        None => totals.push((opc_channel, count)),
+                    }
+                }
+                match totals.iter().find(|&&(_, count)| count > MAX_OPC_PIXELS) {
+                    Some(&(opc_channel, _)) => Err(format!(
+                        "opc channel {} exceeds {} pixels",
+                        opc_channel, MAX_OPC_PIXELS
+                    )),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    pub fn build(&self, mote_dev: &str) -> Box<LedOutput> {
+        match self {
+            &OutputConfig::Mote => Box::new(MoteOutput {
+                mote: mote::Mote::new(mote_dev, true),
+            }),
+            &OutputConfig::Terminal => Box::new(TerminalOutput::new(io::stdout())),
+            &OutputConfig::Opc {
+                ref address,
+                ref channels,
+                reconnect_secs,
+            } => Box::new(OpcOutput::new(
+                address,
+                channels.clone(),
+                Duration::from_secs(reconnect_secs),
+            )),
+        }
+    }
+}
+
+/// Number of channels of the Mote, each driving a stick of `MOTE_PIXELS` pixels.
+const MOTE_CHANNELS: usize = 4;
+const MOTE_PIXELS: usize = 16;
+
+pub struct MoteOutput {
+    mote: mote::Mote,
+}
+
+/// Lays out the channels as the Mote expects them, with all the pixels of its channels; missing
+/// ones are black.
+fn mote_frame(channels: &[Vec<rgb::RGB8>]) -> Vec<rgb::RGB8> {
+    let mut pixels = vec![BLACK; MOTE_CHANNELS * MOTE_PIXELS];
+    for (i, channel) in channels.iter().take(MOTE_CHANNELS).enumerate() {
+        let count = channel.len().min(MOTE_PIXELS);
+        pixels[i * MOTE_PIXELS..i * MOTE_PIXELS + count].copy_from_slice(&channel[..count]);
+    }
+    pixels
+}
+
+impl LedOutput for MoteOutput {
+    fn write(&mut self, channels: &[Vec<rgb::RGB8>]) -> Result<(), String> {
+        self.mote.write(&mote_frame(channels));
+        Ok(())
+    }
+}
+
+/// Draws each channel as a line of blocks in 24-bit ANSI colors, redrawn in place.
+pub struct TerminalOutput<W: Write> {
+    out: W,
+    /// Last frame drawn, to avoid redrawing identical frames.
+    last: Option<Vec<Vec<rgb::RGB8>>>,
+}
+
+impl<W: Write> TerminalOutput<W> {
+    pub fn new(out: W) -> TerminalOutput<W> {
+        TerminalOutput {
+            out: out,
+            last: None,
+        }
+    }
+
+    fn draw(&mut self, channels: &[Vec<rgb::RGB8>]) -> io::Result<()> {
+        let mut s = String::new();
+        if let Some(ref last) = self.last {
+            // Move back to the first line of the previous frame.
+            s.push_str(&format!("\x1b[{}A", last.len()));
+        }
+        for channel in channels {
+            s.push('\r');
+            for p in channel {
+                s.push_str(&format!("\x1b[38;2;{};{};{}m\u{2588}\u{2588}", p.r, p.g, p.b));
+            }
+            s.push_str("\x1b[0m\x1b[K\n");
+        }
+        self.out.write_all(s.as_bytes())?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write> LedOutput for TerminalOutput<W> {
+    fn write(&mut self, channels: &[Vec<rgb::RGB8>]) -> Result<(), String> {
+        if self.last.as_ref().map_or(false, |l| &l[..] == channels) {
+            return Ok(());
+        }
+        self.draw(channels)
+            .map_err(|e| format!("could not draw on terminal: {}", e))?;
+        self.last = Some(channels.to_vec());
+        Ok(())
+    }
+}
+
+/// OPC command setting the colors of the pixels of a channel.
+const OPC_SET_PIXEL_COLORS: u8 = 0;
+
+/// Maximum number of pixels in an OPC message, whose length is 16 bits.
+const MAX_OPC_PIXELS: usize = 0xFFFF / 3;
+
+/// Time after which a frame that could not be sent is abandoned, so as not to hold up rendering.
+const OPC_WRITE_TIMEOUT_MS: u64 = 100;
+
+/// Time after which connecting to each address of the server is abandoned, for the same reason.
+const OPC_CONNECT_TIMEOUT_MS: u64 = 100;
+
+/// Groups the pixels of each output channel by OPC channel, in order.
+fn opc_frame(channels: &[Vec<rgb::RGB8>], opc_channels: &[u8]) -> Vec<(u8, Vec<rgb::RGB8>)> {
+    let mut frame: Vec<(u8, Vec<rgb::RGB8>)> = vec![];
+    for (i, pixels) in channels.iter().enumerate() {
+        let opc_channel = opc_channels.get(i).cloned().unwrap_or(0);
+        match frame.iter().position(|&(c, _)| c == opc_channel) {
+            Some(j) => frame[j].1.extend_from_slice(pixels),
+            None => frame.push((opc_channel, pixels.clone())),
+        }
+    }
+    frame
+}
+
+/// Streams frames to an Open Pixel Control server over TCP, reconnecting when the connection is
+/// lost.
+///
+/// See http://openpixelcontrol.org/.
+pub struct OpcOutput {
+    address: String,
+    channels: Vec<u8>,
+    reconnect_interval: Duration,
+    stream: Option<TcpStream>,
+    /// Time before which no reconnection is attempted, after a failure.
+    retry_at: Option<Instant>,
+}
+
+impl OpcOutput {
+    pub fn new(address: &str, channels: Vec<u8>, reconnect_interval: Duration) -> OpcOutput {
+        OpcOutput {
+            address: address.to_string(),
+            channels: channels,
+            reconnect_interval: reconnect_interval,
+            stream: None,
+            retry_at: None,
+        }
+    }
+
+    /// Connects to the first address of the server that accepts the connection.
+    ///
+    /// Resolving the address may still block, unless it is an IP address.
+    fn connect(&mut self) -> io::Result<()> {
+        let timeout = Duration::from_millis(OPC_CONNECT_TIMEOUT_MS);
+        let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no address found");
+        let mut connected = None;
+        for addr in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    connected = Some(stream);
+                    break;
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        let stream = connected.ok_or(last_err)?;
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(Some(Duration::from_millis(OPC_WRITE_TIMEOUT_MS)))?;
+        info!("connected to opc server {}", self.address);
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    /// Encodes a frame as one "set pixel colors" message per OPC channel.
+    fn encode(&self, channels: &[Vec<rgb::RGB8>]) -> Vec<u8> {
+        let mut message = vec![];
+        for (opc_channel, pixels) in opc_frame(channels, &self.channels) {
+            let len = pixels.len() * 3;
+            message.extend_from_slice(&[
+                opc_channel,
+                OPC_SET_PIXEL_COLORS,
+                (len >> 8) as u8,
+                len as u8,
+            ]);
+            for p in pixels {
+                message.extend_from_slice(&[p.r, p.g, p.b]);
+            }
+        }
+        message
+    }
+}
+
+impl LedOutput for OpcOutput {
+    /// Frames are dropped while waiting to reconnect; errors are only returned when connecting or
+    /// sending fails, so at most once per reconnection interval.
+    fn write(&mut self, channels: &[Vec<rgb::RGB8>]) -> Result<(), String> {
+        if self.stream.is_none() {
+            if self.retry_at.map_or(false, |t| Instant::now() < t) {
+                return Ok(());
+            }
+            if let Err(err) = self.connect() {
+                self.retry_at = Some(Instant::now() + self.reconnect_interval);
+                return Err(format!("could not connect to opc server {}: {}", self.address, err));
+            }
+        }
+        let message = self.encode(channels);
+        let result = match self.stream {
+            Some(ref mut stream) => stream.write_all(&message),
+            None => Ok(()),
+        };
+        if let Err(err) = result {
+            self.stream = None;
+            self.retry_at = Some(Instant::now() + self.reconnect_interval);
+            return Err(format!("could not write to opc server {}: {}", self.address, err));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_terminal_output() {
+    use color::RED;
+
+    let mut output = TerminalOutput::new(vec![]);
+    output.write(&[vec![RED, BLACK], vec![]]).unwrap();
+    output.write(&[vec![RED, BLACK], vec![]]).unwrap();
+    assert_eq!(
+        "\r\x1b[38;2;255;0;0m\u{2588}\u{2588}\x1b[38;2;0;0;0m\u{2588}\u{2588}\x1b[0m\x1b[K\n\
+         \r\x1b[0m\x1b[K\n",
+        String::from_utf8(output.out.clone()).unwrap()
+    );
+
+    output.write(&[vec![BLACK, BLACK], vec![]]).unwrap();
+    // Only changed frames are drawn, over the previous one.
+    let out = String::from_utf8(output.out).unwrap();
+    assert!(out.ends_with(
+        "\x1b[2A\r\x1b[38;2;0;0;0m\u{2588}\u{2588}\x1b[38;2;0;0;0m\u{2588}\u{2588}\x1b[0m\x1b[K\n\
+         \r\x1b[0m\x1b[K\n"
+    ));
+}
+
+#[test]
+fn test_mote_output() {
+    use color::RED;
+
+    let pixels = mote_frame(&[vec![RED; 2], vec![], vec![RED; 16]]);
+    assert_eq!(64, pixels.len());
+    assert_eq!(vec![RED, RED, BLACK], &pixels[..3]);
+    assert_eq!(vec![BLACK; 30], &pixels[2..32]);
+    assert_eq!(vec![RED; 16], &pixels[32..48]);
+    assert_eq!(vec![BLACK; 16], &pixels[48..]);
+
+    assert_eq!(Ok(()), OutputConfig::Mote.validate(&[16, 8]));
+    assert!(OutputConfig::Mote.validate(&[16, 17]).is_err());
+    assert!(OutputConfig::Mote.validate(&[16; 5]).is_err());
+}
+
+#[test]
+fn test_opc_output() {
+    use color::{BLUE, GREEN, RED};
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let server = thread::spawn(move || {
+        let mut message = [0; 20];
+        listener
+            .accept()
+            .unwrap()
+            .0
+            .read_exact(&mut message)
+            .unwrap();
+        message
+    });
+
+    let mut output = OpcOutput::new(&address, vec![1, 2, 1], Duration::from_secs(0));
+    output
+        .write(&[vec![RED], vec![GREEN, BLUE], vec![BLUE]])
+        .unwrap();
+    assert_eq!(
+        [
+            1, 0, 0, 6, 255, 0, 0, 0, 0, 255, 2, 0, 0, 6, 0, 255, 0, 0, 0, 255,
+        ],
+        server.join().unwrap()[..]
+    );
+}
+
+#[test]
+fn test_opc_reconnection() {
+    use std::net::TcpListener;
+
+    // Find a free port, and close it.
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let mut output = OpcOutput::new(&address, vec![], Duration::from_secs(3600));
+    assert!(output.write(&[vec![]]).is_err());
+    // No reconnection is attempted until the interval elapsed.
+    let listener = TcpListener::bind(address.as_str()).unwrap();
+    assert_eq!(Ok(()), output.write(&[vec![]]));
+    assert!(output.stream.is_none());
+
+    output.retry_at = None;
+    assert_eq!(Ok(()), output.write(&[vec![]]));
+    assert!(listener.accept().is_ok());
+}
+
+#[test]
+fn test_opc_validation() {
+    let opc = |channels: Vec<u8>| OutputConfig::Opc {
+        address: "localhost:7890".to_string(),
+        channels: channels,
+        reconnect_secs: 5,
+    };
+    assert_eq!(Ok(()), opc(vec![]).validate(&[16, 16]));
+    assert!(opc(vec![0]).validate(&[16, 16]).is_err());
+    assert_eq!(Ok(()), opc(vec![0, 1]).validate(&[20000, 20000]));
+    assert!(opc(vec![]).validate(&[20000, 20000]).is_err());
+}
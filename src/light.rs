@@ -1,25 +1,88 @@
 extern crate rgb;
 
 use std::string::ToString;
+use std::sync::Arc;
 
 use color;
-use device::Device;
+use device::{Device, Metadata};
+use effect;
 use google_actions;
-use google_actions::{ExecuteResponseCommand, Name, Params, SyncResponseDevice};
+use google_actions::{ColorTemperatureRange, DeviceCommand, ErrorCode, Params, SpectrumHsv,
+                     SyncResponseDevice, SyncResponseDeviceAttributes};
+use homegraph::HomeGraph;
 
 pub struct Light {
     pub id: String,
     pub name: String,
+    pub metadata: Metadata,
     pub status: LightStatus,
     pub available_light_modes: Vec<LightMode>,
+    /// Color model reported for `LightMode::ColorSpectrum`.
+    pub color_model: ColorModel,
+    /// Supported color temperatures, only relevant if `LightMode::ColorTemperature` is available.
+    pub temperature_range: TemperatureRange,
     pub type_: LightType,
+    /// Pixels driven by the light, in order.
+    pub pixels: Vec<PixelRange>,
     pub color_func: Box<color::ColorFunc>,
+    /// Effect currently driving `color_func`, if any, as opposed to a solid color.
+    pub effect: Option<effect::Effect>,
+    pub homegraph: Option<Arc<HomeGraph>>,
 }
 
+/// Range of consecutive pixels on an output channel.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PixelRange {
+    #[serde(default)]
+    pub channel: usize,
+    pub offset: usize,
+    pub count: usize,
+}
+
+/// Range of color temperatures, in Kelvin.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TemperatureRange {
+    pub min_k: u64,
+    pub max_k: u64,
+}
+
+impl Default for TemperatureRange {
+    fn default() -> TemperatureRange {
+        TemperatureRange {
+            min_k: 2000,
+            max_k: 9000,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorModel {
+    Rgb,
+    Hsv,
+}
+
+impl Default for ColorModel {
+    fn default() -> ColorModel {
+        ColorModel::Rgb
+    }
+}
+
+impl ToString for ColorModel {
+    fn to_string(&self) -> String {
+        match self {
+            &ColorModel::Rgb => "rgb".to_string(),
+            &ColorModel::Hsv => "hsv".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub enum LightMode {
     OnOff,
     Brightness,
-    ColorSpectrum, // TODO: Temperature.
+    ColorSpectrum,
+    ColorTemperature,
 }
 
 impl ToString for LightMode {
@@ -28,11 +91,13 @@ impl ToString for LightMode {
             &LightMode::OnOff => "action.devices.traits.OnOff".to_string(),
             &LightMode::Brightness => "action.devices.traits.Brightness".to_string(),
             &LightMode::ColorSpectrum => "action.devices.traits.ColorSpectrum".to_string(),
+            &LightMode::ColorTemperature => "action.devices.traits.ColorTemperature".to_string(),
         }
     }
 }
 
-#[allow(unused)]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "UPPERCASE")]
 pub enum LightType {
     Light,
     Outlet,
@@ -49,32 +114,84 @@ impl ToString for LightType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LightStatus {
     pub on: bool,
     pub brightness: u8,
-    pub color: rgb::RGB8,
+    pub color: LightColor,
 }
 
-impl From<google_actions::Params> for LightStatus {
-    fn from(params: google_actions::Params) -> LightStatus {
-        LightStatus {
-            on: params.on.unwrap_or(false),
-            brightness: params.brightness.unwrap_or(100),
-            color: to_rgb(params.color.unwrap().spectrum_rgb.unwrap_or(0)),
+/// Color of a light, in the mode it was last set in.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LightColor {
+    Spectrum(#[serde(with = "rgb_value")] rgb::RGB8),
+    /// Spectrum color set through the HSV model, kept as such to avoid rounding it to 8 bits per
+    /// channel before applying the brightness.
+    Hsv(color::Hsv),
+    /// Color temperature, in Kelvin.
+    Temperature(u64),
+}
+
+impl LightColor {
+    /// Color to output on RGB hardware, scaled by the given brightness percentage.
+    pub fn to_rgb(&self, brightness: u8) -> rgb::RGB8 {
+        let scale = brightness as f32 / 100.0;
+        let c = match self {
+            &LightColor::Spectrum(c) => c,
+            &LightColor::Hsv(hsv) => {
+                return color::Hsv {
+                    value: hsv.value * scale,
+                    ..hsv
+                }.to_rgb()
+            }
+            &LightColor::Temperature(k) => color::kelvin_to_rgb(k),
+        };
+        rgb::RGB8 {
+            r: (c.r as f32 * scale).round() as u8,
+            g: (c.g as f32 * scale).round() as u8,
+            b: (c.b as f32 * scale).round() as u8,
+        }
+    }
+
+    /// Converts spectrum colors to the given color model; color temperatures are left unchanged.
+    fn to_model(self, model: &ColorModel) -> LightColor {
+        match (self, model) {
+            (LightColor::Hsv(hsv), &ColorModel::Rgb) => LightColor::Spectrum(hsv.to_rgb()),
+            (LightColor::Spectrum(c), &ColorModel::Hsv) => LightColor::Hsv(color::Hsv::from_rgb(c)),
+            (c, _) => c,
         }
     }
 }
 
 impl Into<google_actions::Params> for LightStatus {
     fn into(self) -> google_actions::Params {
+        let (temperature, spectrum_rgb, spectrum_hsv) = match self.color {
+            LightColor::Spectrum(c) => (None, Some(from_rgb(&c)), None),
+            LightColor::Hsv(hsv) => (
+                None,
+                None,
+                Some(SpectrumHsv {
+                    hue: hsv.hue,
+                    saturation: hsv.saturation,
+                    value: hsv.value,
+                }),
+            ),
+            LightColor::Temperature(k) => (Some(k), None, None),
+        };
+        let name = match self.color {
+            LightColor::Spectrum(c) => color::rgb_name(c),
+            LightColor::Hsv(hsv) => color::rgb_name(hsv.to_rgb()),
+            LightColor::Temperature(k) => color::temperature_name(k),
+        };
         google_actions::Params {
+            online: Some(true),
             on: Some(self.on),
             brightness: Some(self.brightness),
             color: Some(google_actions::Color {
-                name: None,
-                temperature: None,
-                spectrum_rgb: Some(from_rgb(&self.color)),
+                name: name.map(|n| n.to_string()),
+                temperature: temperature,
+                spectrum_rgb: spectrum_rgb,
+                spectrum_hsv: spectrum_hsv,
             }),
             ..google_actions::Params::default()
         }
@@ -86,11 +203,11 @@ impl Default for LightStatus {
         LightStatus {
             on: false,
             brightness: 100,
-            color: rgb::RGB8 {
+            color: LightColor::Spectrum(rgb::RGB8 {
                 r: 0xFF,
                 g: 0xFF,
                 b: 0xFF,
-            },
+            }),
         }
     }
 }
@@ -107,6 +224,21 @@ fn from_rgb(c: &rgb::RGB8) -> u64 {
     (c.r as u64) << 16 | (c.g as u64) << 8 | (c.b as u64) << 0
 }
 
+/// Serializes colors as `0xRRGGBB` integers, as in the Google protocol.
+mod rgb_value {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::{from_rgb, rgb, to_rgb};
+
+    pub fn serialize<S: Serializer>(c: &rgb::RGB8, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(from_rgb(c))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<rgb::RGB8, D::Error> {
+        u64::deserialize(deserializer).map(to_rgb)
+    }
+}
+
 impl Light {
     pub fn set_on(&mut self, s: bool) {
         debug!("set_on: {:?}", s);
@@ -122,27 +254,63 @@ impl Light {
     }
 
     pub fn set_color(&mut self, c: rgb::RGB8) {
+        self.set_light_color(LightColor::Spectrum(c));
+    }
+
+    pub fn set_hsv(&mut self, hsv: color::Hsv) {
+        self.set_light_color(LightColor::Hsv(hsv));
+    }
+
+    fn set_light_color(&mut self, c: LightColor) {
         debug!("set_color: {:?}", c);
-        self.status.color = c;
+        self.status.color = c.to_model(&self.color_model);
         self.status.on = true;
         self.output();
     }
 
+    pub fn set_color_temperature(&mut self, k: u64) {
+        self.set_light_color(LightColor::Temperature(k));
+    }
+
+    pub fn set_effect(&mut self, effect: effect::Effect) {
+        debug!("set_effect: {:?}", effect);
+        self.color_func = effect.color_func();
+        self.effect = Some(effect);
+    }
+
+    /// Restores a previous state, e.g. when deactivating a scene.
+    pub fn restore(&mut self, status: LightStatus, effect: Option<effect::Effect>) {
+        debug!("restore: {:?} {:?}", status, effect);
+        self.status = status;
+        self.output();
+        if let Some(effect) = effect {
+            self.set_effect(effect);
+        }
+    }
+
+    /// Resolves a color name, using a color temperature for shades of white if supported.
+    fn named_color(&self, name: &str) -> Option<LightColor> {
+        if let Some(k) = color::temperature_by_name(name) {
+            if self.available_light_modes.contains(&LightMode::ColorTemperature) {
+                return Some(LightColor::Temperature(k));
+            }
+            return Some(LightColor::Spectrum(color::kelvin_to_rgb(k)));
+        }
+        color::rgb_by_name(name).map(LightColor::Spectrum)
+    }
+
     fn output(&mut self) {
-        let scale = if self.status.on {
+        let brightness = if self.status.on {
             self.status.brightness
         } else {
             0
-        } as u32;
-        let scaled_r = (self.status.color.r as u32 * scale / 100) as u8;
-        let scaled_g = (self.status.color.g as u32 * scale / 100) as u8;
-        let scaled_b = (self.status.color.b as u32 * scale / 100) as u8;
-        let c = rgb::RGB8 {
-            r: scaled_r,
-            g: scaled_g,
-            b: scaled_b,
         };
+        let c = self.status.color.to_rgb(brightness);
         self.color_func = Box::new(color::SolidColor { c: c });
+        self.effect = None;
+        if let Some(ref homegraph) = self.homegraph {
+            homegraph.report_state(&self.id, self.status.clone().into());
+        }
     }
 }
 
@@ -151,47 +319,146 @@ impl Device for Light {
         self.id.clone()
     }
 
-    fn sync(&self) -> Option<SyncResponseDevice> {
-        Option::Some(SyncResponseDevice {
+    fn sync(&self) -> SyncResponseDevice {
+        let mut attributes = SyncResponseDeviceAttributes::default();
+        if self.available_light_modes.contains(&LightMode::ColorSpectrum) {
+            attributes.color_model = Some(self.color_model.to_string());
+        }
+        if self.available_light_modes.contains(&LightMode::ColorTemperature) {
+            attributes.color_temperature_range = Some(ColorTemperatureRange {
+                temperature_min_k: self.temperature_range.min_k,
+                temperature_max_k: self.temperature_range.max_k,
+            });
+        }
+        SyncResponseDevice {
             id: self.id.clone(),
             type_: self.type_.to_string(),
             traits: self.available_light_modes
                 .iter()
                 .map(LightMode::to_string)
                 .collect(),
-            name: Name {
-                default_name: vec![self.name.to_string()],
-                name: Some(self.name.clone()),
-                nicknames: vec![],
+            name: self.metadata.name(&self.name),
+            will_report_state: self.homegraph.is_some(),
+            device_info: self.metadata.device_info.clone(),
+            room_hint: self.metadata.room_hint.clone(),
+            structure_hint: self.metadata.structure_hint.clone(),
+            attributes: if attributes == SyncResponseDeviceAttributes::default() {
+                None
+            } else {
+                Some(attributes)
             },
-            will_report_state: false,
-            device_info: None,
-            room_hint: None,
-            structure_hint: None,
-            attributes: None,
-        })
+        }
     }
 
-    fn query(&self) -> Option<Params> {
-        Option::Some(self.status.clone().into())
+    fn query(&self) -> Result<Params, ErrorCode> {
+        Ok(self.status.clone().into())
     }
 
-    fn execute(&mut self, params: &Params) -> Option<ExecuteResponseCommand> {
-        if let Some(s) = params.on {
-            self.set_on(s);
-        }
-        if let Some(s) = params.brightness {
-            self.set_brightness(s);
-        }
-        if let Some(ref s) = params.color {
-            if let Some(s) = s.spectrum_rgb {
-                self.set_color(to_rgb(s));
+    fn execute(&mut self, command: &DeviceCommand) -> Result<Params, ErrorCode> {
+        match command {
+            &DeviceCommand::OnOff { on } => self.set_on(on),
+            &DeviceCommand::BrightnessAbsolute { brightness } => {
+                if brightness > 100 {
+                    return Err(ErrorCode::ValueOutOfRange);
+                }
+                self.set_brightness(brightness);
+            }
+            &DeviceCommand::ColorAbsolute { ref color } => {
+                match (color.temperature, &color.spectrum_hsv, color.spectrum_rgb) {
+                    (Some(k), _, _) => {
+                        if k < self.temperature_range.min_k || k > self.temperature_range.max_k {
+                            return Err(ErrorCode::ValueOutOfRange);
+                        }
+                        self.set_color_temperature(k);
+                    }
+                    (None, &Some(ref hsv), _) => {
+                        if hsv.hue < 0.0 || hsv.hue > 360.0 || hsv.saturation < 0.0
+                            || hsv.saturation > 1.0 || hsv.value < 0.0
+                            || hsv.value > 1.0
+                        {
+                            return Err(ErrorCode::ValueOutOfRange);
+                        }
+                        self.set_hsv(color::Hsv {
+                            hue: hsv.hue,
+                            saturation: hsv.saturation,
+                            value: hsv.value,
+                        });
+                    }
+                    (None, &None, Some(c)) => {
+                        if c > 0xFFFFFF {
+                            return Err(ErrorCode::ValueOutOfRange);
+                        }
+                        self.set_color(to_rgb(c));
+                    }
+                    (None, &None, None) => match color.name {
+                        Some(ref name) => {
+                            let c = self.named_color(name).ok_or(ErrorCode::NotSupported)?;
+                            self.set_light_color(c);
+                        }
+                        None => return Err(ErrorCode::NotSupported),
+                    },
+                }
             }
+            _ => return Err(ErrorCode::NotSupported),
         }
-        Option::Some(ExecuteResponseCommand {
-            ids: vec![self.id()],
-            status: "SUCCESS".to_string(),
-            states: self.status.clone().into(),
-        })
+        self.query()
     }
 }
+
+#[test]
+fn test_named_color() {
+    use google_actions::Color;
+    use testing::build_hub;
+
+    let hub = build_hub(
+        r#"
+{
+  "lights": [{
+    "id": "111",
+    "name": "Mote",
+    "traits": ["OnOff", "ColorSpectrum"],
+    "pixels": [{ "channel": 0, "offset": 0, "count": 8 }]
+  }, {
+    "id": "222",
+    "name": "Bulb",
+    "traits": ["OnOff", "ColorTemperature"],
+    "pixels": [{ "channel": 0, "offset": 8, "count": 8 }]
+  }]
+}
+"#,
+    );
+    let command = |name: &str| DeviceCommand::ColorAbsolute {
+        color: Color {
+            name: Some(name.to_string()),
+            temperature: None,
+            spectrum_rgb: None,
+            spectrum_hsv: None,
+        },
+    };
+    let supports = |light: &Light, command: &DeviceCommand| {
+        let traits = light.sync().traits;
+        command
+            .required_traits()
+            .iter()
+            .any(|r| traits.iter().any(|t| t == r))
+    };
+
+    // Shades of white are approximated by lights without ColorTemperature.
+    let mut rgb = hub.lights[0].lock().unwrap();
+    assert!(supports(&*rgb, &command("warm white")));
+    rgb.execute(&command("warm white")).unwrap();
+    assert_eq!(
+        LightColor::Spectrum(color::kelvin_to_rgb(2700)),
+        rgb.status.color
+    );
+    assert!(supports(&*rgb, &command("red")));
+    rgb.execute(&command("red")).unwrap();
+    assert_eq!(LightColor::Spectrum(color::RED), rgb.status.color);
+    assert_eq!(Err(ErrorCode::NotSupported), rgb.execute(&command("plaid")));
+
+    let mut white = hub.lights[1].lock().unwrap();
+    assert!(supports(&*white, &command("warm white")));
+    white.execute(&command("warm white")).unwrap();
+    assert_eq!(LightColor::Temperature(2700), white.status.color);
+    assert!(!supports(&*white, &command("red")));
+}
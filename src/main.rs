@@ -5,13 +5,17 @@ extern crate env_logger;
 extern crate futures;
 extern crate getopts;
 extern crate mote;
+extern crate rand;
 extern crate reqwest;
 extern crate rgb;
+extern crate ring;
 extern crate rocket;
 extern crate rocket_contrib;
 extern crate scroll_phat_hd;
+extern crate serde;
 extern crate serde_json;
 extern crate staticfile;
+extern crate time;
 extern crate tokio_core;
 extern crate unicase;
 extern crate url;
@@ -26,9 +30,10 @@ extern crate serde_derive;
 extern crate maplit;
 
 use std::env;
-use std::sync::{Arc, Mutex};
+use std::io;
+use std::process;
 use std::thread;
-use std::time;
+use std::time::Duration;
 
 use getopts::Options;
 use rocket::State;
@@ -38,167 +43,58 @@ use unicase::UniCase;
 use rocket::http::hyper::header::{AccessControlAllowHeaders, AccessControlAllowOrigin};
 
 mod google_actions;
-use google_actions::{ActionRequest, ExecuteResponse, ExecuteResponsePayload, QueryResponse,
-                     QueryResponsePayload, SyncResponse, SyncResponsePayload};
+use google_actions::{ActionRequest, ActionResponse};
 
 mod light;
-use light::{Light, LightMode, LightStatus, LightType};
+
+mod led;
 
 mod thermostat;
-use thermostat::{TemperatureUnit, Thermostat, ThermostatMode, ThermostatStatus};
+
+mod hvac;
+
+mod sensor;
+
+mod schedule;
 
 mod scene;
-use scene::Scene;
 
 mod device;
-use device::Device;
 
 mod color;
 
-mod oauth;
-
-const BLACK: rgb::RGB8 = rgb::RGB8 { r: 0, g: 0, b: 0 };
+mod effect;
 
-#[derive(Serialize, Deserialize)]
-#[serde(untagged)]
-enum ActionResponse {
-    Sync(SyncResponse),
-    Query(QueryResponse),
-    Execute(ExecuteResponse),
-}
-
-struct Hub {
-    devices: Vec<Arc<Mutex<Device>>>,
-    proxy_urls: Vec<String>,
-}
+mod config;
 
-// Test
-#[post("/action", format = "application/json", data = "<message>")]
-fn action(message: Json<ActionRequest>, state: State<Hub>) -> Option<Json<ActionResponse>> {
-    info!("action_request: {:?}", message);
-    for input in message.0.inputs {
-        match input.intent.as_ref() {
-            "action.devices.SYNC" => {
-                let mut response = SyncResponse {
-                    request_id: message.0.request_id.clone(),
-                    payload: SyncResponsePayload {
-                        agent_user_id: "1111".to_string(),
-                        devices: vec![],
-                    },
-                };
-
-                for device in &state.devices {
-                    let device = device.lock().unwrap();
-                    response.payload.devices.push(device.sync().unwrap());
-                }
+mod fulfillment;
 
-                let client = reqwest::Client::new();
-                for url in &state.proxy_urls {
-                    debug!("proxy url: {:?}", url);
-                    // XXX
-                    //let mut res = client.post(url).body(body.to_string()).send().unwrap();
-                    //debug!("proxy response: {:?}", res);
-                    //let proxy_response = res.json::<SyncResponse>().unwrap();
-                    //response
-                    //.payload
-                    //.devices
-                    //.extend(proxy_response.payload.devices);
-                }
-                info!("response: {:?}", serde_json::to_string(&response));
+mod homegraph;
 
-                return Some(Json(ActionResponse::Sync(response)));
-            }
-            "action.devices.QUERY" => {
-                let mut response = QueryResponse {
-                    request_id: message.0.request_id.clone(),
-                    payload: QueryResponsePayload {
-                        devices: btreemap!{},
-                    },
-                };
-
-                if let Some(payload) = input.payload {
-                    for request_device in payload.devices {
-                        for device in &state.devices {
-                            let device = device.lock().unwrap();
-                            if request_device.id == device.id() {
-                                response
-                                    .payload
-                                    .devices
-                                    .insert(device.id(), device.query().unwrap());
-                            }
-                        }
-                    }
-                }
+mod hub;
+use hub::Hub;
 
-                let client = reqwest::Client::new();
-                for url in &state.proxy_urls {
-                    debug!("proxy url: {:?}", url);
-                    // XXX
-                    //let mut res = client.post(url).body(body.to_string()).send().unwrap();
-                    //debug!("proxy response: {:?}", res);
-                    //let proxy_response = res.json::<QueryResponse>().unwrap();
-                    //response
-                    //.payload
-                    //.devices
-                    //.extend(proxy_response.payload.devices);
-                }
+mod oauth;
+use oauth::{OAuth, User};
 
-                return Some(Json(ActionResponse::Query(response)));
-            }
-            "action.devices.EXECUTE" => {
-                let mut response = ExecuteResponse {
-                    request_id: message.0.request_id.clone(),
-                    payload: ExecuteResponsePayload {
-                        error_code: None,
-                        debug_string: None,
-                        commands: vec![],
-                    },
-                };
-
-                if let Some(ref p) = input.payload {
-                    for command in &p.commands {
-                        debug!("command: {:?}", command);
-                        for execution in &command.execution {
-                            debug!("execution: {:?}", execution);
-                            for request_device in &command.devices {
-                                debug!("request_device: {:?}", request_device);
-                                for device in &state.devices {
-                                    let mut device = device.lock().unwrap();
-                                    if request_device.id == device.id() {
-                                        response
-                                            .payload
-                                            .commands
-                                            .push(device.execute(&execution.params).unwrap());
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+mod proxy;
 
-                let client = reqwest::Client::new();
-                for url in &state.proxy_urls {
-                    debug!("proxy url: {:?}", url);
-                    // XXX
-                    //let mut res = client.post(url).body(body.to_string()).send().unwrap();
-                    //debug!("proxy response: {:?}", res);
-                    //let proxy_response = res.json::<ExecuteResponse>().unwrap();
-                    //response
-                    //.payload
-                    //.commands
-                    //.extend(proxy_response.payload.commands);
-                }
+mod render;
 
-                return Some(Json(ActionResponse::Execute(response)));
-            }
-            i => {
-                debug!("unsupported intent: {:?}", i);
-                return None;
-            }
-        }
-    }
+#[cfg(test)]
+mod testing;
 
-    return None;
+#[post("/action", format = "application/json", data = "<message>")]
+fn action(
+    message: Json<ActionRequest>,
+    user: User,
+    state: State<Hub>,
+    oauth: State<OAuth>,
+) -> Json<ActionResponse> {
+    info!("action_request from {:?}: {:?}", user.id, message);
+    let response = fulfillment::fulfill(&state, &oauth, &user.id, message.0);
+    info!("response: {:?}", serde_json::to_string(&response));
+    Json(response)
 }
 
 fn main() {
@@ -210,6 +106,12 @@ fn main() {
     opts.optopt("", "http_port", "HTTP port to listen on", "N");
     opts.optopt("", "mote_dev", "Serial port connecting to Mote", "FILE");
     opts.optopt("", "display_i2c", "I2C port to use as display", "N");
+    opts.optopt("", "config", "Device configuration file", "FILE");
+    opts.optflag(
+        "",
+        "hash_password",
+        "Read a password from stdin and print its hash for the configuration",
+    );
 
     debug!("parsing args");
     let matches = opts.parse(&args[1..]).unwrap();
@@ -222,194 +124,107 @@ fn main() {
         .opt_str("mote_dev")
         .unwrap_or("/dev/ttyACM0".to_string());
     let display_i2c = matches.opt_str("display_i2c").unwrap_or("".to_string());
+    let config_path = matches
+        .opt_str("config")
+        .unwrap_or("config.json".to_string());
     debug!("args parsed");
 
-    let bedroom_lights = Arc::new(Mutex::new(Light {
-        id: "111".to_string(),
-        name: "Bedroom lights".to_string(),
-        status: LightStatus::default(),
-        type_: LightType::Light,
-        available_light_modes: vec![
-            LightMode::OnOff,
-            LightMode::Brightness,
-            LightMode::ColorSpectrum,
-        ],
-        color_func: Box::new(color::SolidColor { c: BLACK }),
-    }));
-
-    let kitchen_lights = Arc::new(Mutex::new(Light {
-        id: "222".to_string(),
-        name: "Kitchen lights".to_string(),
-        status: LightStatus::default(),
-        type_: LightType::Light,
-        available_light_modes: vec![
-            LightMode::OnOff,
-            LightMode::Brightness,
-            LightMode::ColorSpectrum,
-        ],
-        color_func: Box::new(color::SolidColor { c: BLACK }),
-    }));
-
-    let bathroom_lights = Arc::new(Mutex::new(Light {
-        id: "333".to_string(),
-        name: "Bathroom lights".to_string(),
-        status: LightStatus::default(),
-        type_: LightType::Light,
-        available_light_modes: vec![
-            LightMode::OnOff,
-            LightMode::Brightness,
-            LightMode::ColorSpectrum,
-        ],
-        color_func: Box::new(color::SolidColor { c: BLACK }),
-    }));
-
-    let living_room_lights = Arc::new(Mutex::new(Light {
-        id: "444".to_string(),
-        name: "Living Room lights".to_string(),
-        status: LightStatus::default(),
-        type_: LightType::Light,
-        available_light_modes: vec![
-            LightMode::OnOff,
-            LightMode::Brightness,
-            LightMode::ColorSpectrum,
-        ],
-        color_func: Box::new(color::SolidColor { c: BLACK }),
-    }));
-
-    let all_lights = vec![
-        bedroom_lights.clone(),
-        kitchen_lights.clone(),
-        bathroom_lights.clone(),
-        living_room_lights.clone(),
-    ];
-
-    let party_mode = Arc::new(Mutex::new(Scene {
-        id: "1001".to_string(),
-        name: "Party mode".to_string(),
-        reversible: true,
-        lights: all_lights.clone(),
-    }));
-
-    let italian_mode = Arc::new(Mutex::new(Scene {
-        id: "1002".to_string(),
-        name: "Italian Mode".to_string(),
-        reversible: true,
-        lights: all_lights.clone(),
-    }));
-
-    let night_mode = Arc::new(Mutex::new(Scene {
-        id: "1003".to_string(),
-        name: "Night Mode".to_string(),
-        reversible: true,
-        lights: all_lights.clone(),
-    }));
-
-    let strobe_mode = Arc::new(Mutex::new(Scene {
-        id: "1004".to_string(),
-        name: "Strobe Mode".to_string(),
-        reversible: true,
-        lights: all_lights.clone(),
-    }));
-
-    let thermostat = Arc::new(Mutex::new(Thermostat {
-        id: "66".to_string(),
-        name: "Thermostat".to_string(),
-        available_thermostat_modes: vec![ThermostatMode::Off, ThermostatMode::Heat],
-        thermostat_temperature_unit: TemperatureUnit::C,
-        status: ThermostatStatus {
-            mode: ThermostatMode::Off,
-            temperature_setpoint: 21.0,
-            temperature_ambient: 20.0,
-            temperature_setpoint_low: 10.0,
-            temperature_setpoint_high: 30.0,
-            humidity_ambient: 50.0,
-        },
-    }));
-
-    let hub = Hub {
-        devices: vec![
-            bedroom_lights.clone(),
-            kitchen_lights.clone(),
-            bathroom_lights.clone(),
-            living_room_lights.clone(),
-            //party_mode.clone(),
-            //italian_mode.clone(),
-            //night_mode.clone(),
-            //strobe_mode.clone(),
-            //thermostat.clone(),
-        ],
-        proxy_urls: vec![],
+    if matches.opt_present("hash_password") {
+        let mut password = String::new();
+        io::stdin().read_line(&mut password).unwrap();
+        println!(
+            "{}",
+            oauth::hash_password(password.trim_right_matches(|c| c == '\r' || c == '\n'))
+        );
+        return;
+    }
+
+    let config = match config::load(&config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("invalid config: {}", err);
+            process::exit(1);
+        }
+    };
+    let hub = match config.build() {
+        Ok(hub) => hub,
+        Err(err) => {
+            error!("could not initialize devices: {}", err);
+            process::exit(1);
+        }
+    };
+    let render = config.render.clone();
+    if config.oauth.clients.is_empty() {
+        warn!("no oauth clients configured, account linking will not be possible");
+    }
+    let oauth = match oauth::OAuth::new(config.oauth) {
+        Ok(oauth) => oauth,
+        Err(err) => {
+            error!("could not initialize oauth: {}", err);
+            process::exit(1);
+        }
     };
 
-    thread::spawn(move || {
-        let mut mote = mote::Mote::new(&mote_dev, true);
-
-        let mut pixels = [BLACK; 16 * 4];
-        let mut t = 0u64;
-
-        fn update_lights(
-            pixels: &mut [rgb::RGB8; 16 * 4],
-            t: u64,
-            lights: &Arc<Mutex<Light>>,
-            offset: usize,
-        ) {
-            match lights.lock() {
-                Ok(lights) => for i in 0..16 {
-                    let b0 = &pixels.clone()[offset..offset + 16];
-                    let b1 = lights.color_func.step(t, b0);
-                    pixels[i + offset] = b1[i];
-                },
-                Err(err) => error!("could not lock light mutex: {:?}", err),
-            }
+    if let Some(ref homegraph) = hub.homegraph {
+        for user in oauth.linked_users() {
+            homegraph.add_user(&user);
         }
+        // The set of devices may have changed since the last run.
+        let devices: Vec<_> = hub.devices.iter().map(|d| d.lock().unwrap().sync()).collect();
+        homegraph.request_sync_if_changed(&devices);
+    }
 
-        loop {
-            update_lights(&mut pixels, t, &bedroom_lights, 0);
-            update_lights(&mut pixels, t, &kitchen_lights, 16);
-            update_lights(&mut pixels, t, &bathroom_lights, 32);
-            update_lights(&mut pixels, t, &living_room_lights, 48);
-            mote.write(&pixels);
-
-            thread::sleep(time::Duration::from_millis(10));
-            t += 1;
+    let lights = hub.lights.clone();
+    thread::spawn(move || {
+        let outputs = render.outputs.iter().map(|o| o.build(&mote_dev)).collect();
+        render::run(outputs, lights, render)
+    });
+
+    for thermostat in &hub.thermostats {
+        sensor::watch(thermostat.clone());
+    }
+    let thermostats = hub.thermostats.clone();
+    thread::spawn(move || loop {
+        let minute_of_week = schedule::local_minute_of_week();
+        for thermostat in &thermostats {
+            match thermostat.lock() {
+                Ok(mut thermostat) => {
+                    thermostat.run_schedule(minute_of_week);
+                    if thermostat.control() {
+                        thermostat.report_state();
+                    }
+                }
+                Err(err) => error!("could not lock thermostat mutex: {:?}", err),
+            }
         }
+        thread::sleep(Duration::from_secs(1));
     });
 
+    let thermostat = hub.thermostats.first().cloned();
     thread::spawn(move || {
-        // XXX
-        return;
-        let mut display: Box<scroll_phat_hd::display::Display> = if display_i2c == "" {
-            Box::new(scroll_phat_hd::display::UnicodeDisplay::new())
-        } else {
-            // TODO: Parse I2C port.
-            Box::new(scroll_phat_hd::display::I2CDisplay::new(1))
+        // The display is only driven when attached, as the terminal may be used by the lights.
+        let thermostat = match thermostat {
+            Some(ref thermostat) if display_i2c != "" => thermostat.clone(),
+            _ => return,
         };
+        // TODO: Parse I2C port.
+        let mut display: Box<scroll_phat_hd::display::Display> =
+            Box::new(scroll_phat_hd::display::I2CDisplay::new(1));
 
         let mut scroller = scroll_phat_hd::scroller::Scroller::new(&mut *display);
 
         loop {
             match thermostat.lock() {
                 Ok(thermostat) => {
-                    match thermostat.status.mode {
-                        thermostat::ThermostatMode::Off => {
-                            scroller.set_text("--°C");
-                            scroller.show();
-                        }
-                        _ => {
-                            scroller.set_text(&format!(
-                                "{}°C",
-                                thermostat.status.temperature_setpoint
-                            ));
-                            scroller.show();
-                        }
-                    };
+                    scroller.set_text(&thermostat.display_text());
+                    scroller.show();
                 }
                 Err(err) => {
                     error!("could not lock thermostat mutex: {:?}", err);
                 }
             };
 
-            thread::sleep(time::Duration::from_millis(100));
+            thread::sleep(Duration::from_millis(100));
         }
     });
 
@@ -421,6 +236,7 @@ fn main() {
 
     rocket::custom(config, true)
         .manage(hub)
+        .manage(oauth)
         .mount(
             "/",
             routes![
@@ -428,8 +244,11 @@ fn main() {
                 get_action_handler,
                 options_action_handler,
                 oauth::auth,
+                oauth::auth_login,
                 oauth::token,
                 oauth::login,
+                schedule::get_schedule,
+                schedule::put_schedule,
                 start,
                 stop,
                 health,
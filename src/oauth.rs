@@ -1,10 +1,507 @@
 extern crate rocket;
 
-use rocket::request::Form;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::str;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::{OsRng, Rng};
+use ring::{constant_time, digest, pbkdf2};
+use rocket::{Outcome, Request, State};
+use rocket::http::Status;
+use rocket::request::{self, Form, FromRequest};
 use rocket::response::Redirect;
+use rocket::response::content::Html;
+use rocket::response::status::Custom;
 use rocket_contrib::Json;
+use serde_json;
 use url::Url;
 
+#[cfg(test)]
+use testing::TempDir;
+
+/// Length of the random codes and tokens issued by the server.
+const TOKEN_LENGTH: usize = 32;
+
+/// Number of PBKDF2 iterations used when hashing new passwords.
+const PASSWORD_HASH_ITERATIONS: u32 = 100_000;
+
+/// Length of the random salt of new password hashes, in bytes.
+const PASSWORD_SALT_LENGTH: usize = 16;
+
+#[derive(Deserialize, Debug)]
+pub struct OAuthConfig {
+    #[serde(default)]
+    pub clients: Vec<ClientConfig>,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    /// File where issued tokens are persisted across restarts.
+    #[serde(default = "default_token_store")]
+    pub token_store: String,
+    /// Lifetime of authorization codes, in seconds.
+    #[serde(default = "default_code_ttl")]
+    pub code_ttl: u64,
+    /// Lifetime of access tokens, in seconds.
+    #[serde(default = "default_access_token_ttl")]
+    pub access_token_ttl: u64,
+    /// Time, in seconds, after which a refresh token expires if it is not used.
+    #[serde(default = "default_refresh_token_ttl")]
+    pub refresh_token_ttl: u64,
+}
+
+impl Default for OAuthConfig {
+    fn default() -> OAuthConfig {
+        OAuthConfig {
+            clients: vec![],
+            users: vec![],
+            token_store: default_token_store(),
+            code_ttl: default_code_ttl(),
+            access_token_ttl: default_access_token_ttl(),
+            refresh_token_ttl: default_refresh_token_ttl(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClientConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    /// Allowed values of `redirect_uri`, compared verbatim.
+    pub redirect_uris: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UserConfig {
+    pub id: String,
+    /// Hash of the password of the user, as returned by `hash_password`.
+    pub password_hash: String,
+}
+
+fn default_token_store() -> String {
+    "tokens.json".to_string()
+}
+
+fn default_code_ttl() -> u64 {
+    10 * 60
+}
+
+fn default_access_token_ttl() -> u64 {
+    60 * 60
+}
+
+fn default_refresh_token_ttl() -> u64 {
+    90 * 24 * 60 * 60
+}
+
+impl OAuthConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, client) in self.clients.iter().enumerate() {
+            if client.client_id.is_empty() || client.client_secret.is_empty() {
+                return Err("oauth client id and secret must not be empty".to_string());
+            }
+            if self.clients[..i]
+                .iter()
+                .any(|c| c.client_id == client.client_id)
+            {
+                return Err(format!("duplicate oauth client: {:?}", client.client_id));
+            }
+            if client.redirect_uris.is_empty() {
+                return Err(format!(
+                    "oauth client {:?} has no redirect uris",
+                    client.client_id
+                ));
+            }
+            for uri in &client.redirect_uris {
+                Url::parse(uri).map_err(|e| format!("invalid redirect uri {:?}: {}", uri, e))?;
+            }
+        }
+        for (i, user) in self.users.iter().enumerate() {
+            if self.users[..i].iter().any(|u| u.id == user.id) {
+                return Err(format!("duplicate oauth user: {:?}", user.id));
+            }
+            if parse_password_hash(&user.password_hash).is_none() {
+                return Err(format!("invalid password hash for oauth user {:?}", user.id));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An authorization code or token, bound to the client it was issued to and the user who
+/// authorized it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Grant {
+    client_id: String,
+    user_id: String,
+    /// Seconds since the Unix epoch after which the grant is no longer valid, if any.
+    expires: Option<u64>,
+}
+
+impl Grant {
+    fn expired(&self) -> bool {
+        self.expires.map(|e| e <= now()).unwrap_or(false)
+    }
+}
+
+struct Code {
+    grant: Grant,
+    redirect_uri: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct TokenStore {
+    access_tokens: HashMap<String, Grant>,
+    refresh_tokens: HashMap<String, Grant>,
+}
+
+struct OAuthState {
+    /// Outstanding authorization codes; these are short lived, and not persisted.
+    codes: HashMap<String, Code>,
+    tokens: TokenStore,
+}
+
+/// OAuth 2.0 authorization server used for account linking.
+pub struct OAuth {
+    config: OAuthConfig,
+    state: Mutex<OAuthState>,
+}
+
+/// Error returned by the token endpoint, as per RFC 6749 section 5.2.
+#[derive(Serialize, Debug)]
+struct TokenError {
+    error: String,
+}
+
+impl TokenError {
+    fn new(error: &str) -> TokenError {
+        TokenError {
+            error: error.to_string(),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn random_token() -> String {
+    let mut rng = OsRng::new().expect("could not access the OS random number generator");
+    rng.gen_ascii_chars().take(TOKEN_LENGTH).collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect()
+}
+
+/// Hashes the given password with PBKDF2 and a random salt, in the format expected in the
+/// `password_hash` of users: `pbkdf2-sha256$<iterations>$<hex salt>$<hex hash>`.
+pub fn hash_password(password: &str) -> String {
+    let mut rng = OsRng::new().expect("could not access the OS random number generator");
+    let mut salt = [0u8; PASSWORD_SALT_LENGTH];
+    rng.fill_bytes(&mut salt);
+    let mut hash = [0u8; digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(
+        &digest::SHA256,
+        PASSWORD_HASH_ITERATIONS,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "pbkdf2-sha256${}${}${}",
+        PASSWORD_HASH_ITERATIONS,
+        to_hex(&salt),
+        to_hex(&hash)
+    )
+}
+
+/// Splits a password hash into its iterations, salt and hash.
+fn parse_password_hash(password_hash: &str) -> Option<(u32, Vec<u8>, Vec<u8>)> {
+    let parts: Vec<&str> = password_hash.split('$').collect();
+    if parts.len() != 4 || parts[0] != "pbkdf2-sha256" {
+        return None;
+    }
+    let iterations = match parts[1].parse::<u32>() {
+        Ok(iterations) if iterations > 0 => iterations,
+        _ => return None,
+    };
+    let salt = from_hex(parts[2])?;
+    let hash = from_hex(parts[3])?;
+    if hash.len() != digest::SHA256_OUTPUT_LEN {
+        return None;
+    }
+    Some((iterations, salt, hash))
+}
+
+/// Checks the password against the hash, in constant time.
+fn verify_password(password_hash: &str, password: &str) -> bool {
+    match parse_password_hash(password_hash) {
+        Some((iterations, salt, hash)) => pbkdf2::verify(
+            &digest::SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &hash,
+        ).is_ok(),
+        None => false,
+    }
+}
+
+impl OAuth {
+    /// Creates the server, loading previously issued tokens from the token store, if present.
+    pub fn new(config: OAuthConfig) -> Result<OAuth, String> {
+        let mut tokens: TokenStore = if Path::new(&config.token_store).exists() {
+            let file = File::open(&config.token_store)
+                .map_err(|e| format!("could not open {}: {}", config.token_store, e))?;
+            serde_json::from_reader(file)
+                .map_err(|e| format!("could not parse {}: {}", config.token_store, e))?
+        } else {
+            TokenStore::default()
+        };
+        // Refresh tokens issued before they could expire.
+        for grant in tokens.refresh_tokens.values_mut() {
+            if grant.expires.is_none() {
+                grant.expires = Some(now() + config.refresh_token_ttl);
+            }
+        }
+        Ok(OAuth {
+            config: config,
+            state: Mutex::new(OAuthState {
+                codes: HashMap::new(),
+                tokens: tokens,
+            }),
+        })
+    }
+
+    fn client(&self, client_id: &str) -> Option<&ClientConfig> {
+        self.config.clients.iter().find(|c| c.client_id == client_id)
+    }
+
+    fn authenticate_client(&self, client_id: &str, client_secret: &str) -> bool {
+        self.client(client_id)
+            .map(|c| {
+                constant_time::verify_slices_are_equal(
+                    c.client_secret.as_bytes(),
+                    client_secret.as_bytes(),
+                ).is_ok()
+            })
+            .unwrap_or(false)
+    }
+
+    fn valid_redirect_uri(&self, client_id: &str, redirect_uri: &str) -> bool {
+        self.client(client_id)
+            .map(|c| c.redirect_uris.iter().any(|u| u == redirect_uri))
+            .unwrap_or(false)
+    }
+
+    fn authenticate_user(&self, user_id: &str, password: &str) -> bool {
+        self.config
+            .users
+            .iter()
+            .any(|u| u.id == user_id && verify_password(&u.password_hash, password))
+    }
+
+    /// Issues a single-use authorization code for the given client and user.
+    fn issue_code(&self, client_id: &str, user_id: &str, redirect_uri: &str) -> String {
+        let code = random_token();
+        let mut state = self.state.lock().unwrap();
+        state.codes.retain(|_, c| !c.grant.expired());
+        state.codes.insert(
+            code.clone(),
+            Code {
+                grant: Grant {
+                    client_id: client_id.to_string(),
+                    user_id: user_id.to_string(),
+                    expires: Some(now() + self.config.code_ttl),
+                },
+                redirect_uri: redirect_uri.to_string(),
+            },
+        );
+        code
+    }
+
+    fn exchange_code(&self, form: &TokenForm) -> Result<AuthResponse, TokenError> {
+        let code = form.code
+            .as_ref()
+            .ok_or(TokenError::new("invalid_request"))?;
+        let mut state = self.state.lock().unwrap();
+        // Codes are removed as soon as they are presented, so that they can only be used once.
+        let code = state
+            .codes
+            .remove(code)
+            .ok_or(TokenError::new("invalid_grant"))?;
+        if code.grant.expired() || code.grant.client_id != form.client_id
+            || form.redirect_uri.as_ref() != Some(&code.redirect_uri)
+        {
+            return Err(TokenError::new("invalid_grant"));
+        }
+        let refresh_token = random_token();
+        state.tokens.refresh_tokens.retain(|_, g| !g.expired());
+        state.tokens.refresh_tokens.insert(
+            refresh_token.clone(),
+            Grant {
+                expires: Some(now() + self.config.refresh_token_ttl),
+                ..code.grant.clone()
+            },
+        );
+        let response = self.issue_access_token(&mut state, &code.grant, Some(refresh_token));
+        self.save(&state.tokens);
+        Ok(response)
+    }
+
+    /// Revokes all the codes and tokens issued to the given user, e.g. when they unlink their
+    /// account.
+    pub fn revoke_user(&self, user_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.codes.retain(|_, c| c.grant.user_id != user_id);
+        state
+            .tokens
+            .access_tokens
+            .retain(|_, g| g.user_id != user_id);
+        state
+            .tokens
+            .refresh_tokens
+            .retain(|_, g| g.user_id != user_id);
+        self.save(&state.tokens);
+    }
+
+    /// Returns the ids of the users who currently have a linked account.
+    pub fn linked_users(&self) -> BTreeSet<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .tokens
+            .refresh_tokens
+            .values()
+            .filter(|g| !g.expired())
+            .map(|g| g.user_id.clone())
+            .collect()
+    }
+
+    /// Returns the id of the user the given access token was issued to, if the token is valid.
+    pub fn validate_token(&self, access_token: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        match state.tokens.access_tokens.get(access_token) {
+            Some(grant) if !grant.expired() => Some(grant.user_id.clone()),
+            _ => None,
+        }
+    }
+
+    fn refresh(&self, form: &TokenForm) -> Result<AuthResponse, TokenError> {
+        let refresh_token = form.refresh_token
+            .as_ref()
+            .ok_or(TokenError::new("invalid_request"))?;
+        let mut state = self.state.lock().unwrap();
+        let grant = {
+            let grant = state
+                .tokens
+                .refresh_tokens
+                .get_mut(refresh_token)
+                .ok_or(TokenError::new("invalid_grant"))?;
+            if grant.expired() || grant.client_id != form.client_id {
+                return Err(TokenError::new("invalid_grant"));
+            }
+            // Refresh tokens only expire when they are no longer used.
+            grant.expires = Some(now() + self.config.refresh_token_ttl);
+            grant.clone()
+        };
+        let response = self.issue_access_token(&mut state, &grant, None);
+        self.save(&state.tokens);
+        Ok(response)
+    }
+
+    fn issue_access_token(
+        &self,
+        state: &mut OAuthState,
+        grant: &Grant,
+        refresh_token: Option<String>,
+    ) -> AuthResponse {
+        let access_token = random_token();
+        state.tokens.access_tokens.retain(|_, g| !g.expired());
+        state.tokens.access_tokens.insert(
+            access_token.clone(),
+            Grant {
+                expires: Some(now() + self.config.access_token_ttl),
+                ..grant.clone()
+            },
+        );
+        AuthResponse {
+            token_type: "bearer".to_string(),
+            access_token: access_token,
+            refresh_token: refresh_token,
+            expires_in: self.config.access_token_ttl as i64,
+        }
+    }
+
+    fn save(&self, tokens: &TokenStore) {
+        let path = &self.config.token_store;
+        let tmp_path = format!("{}.tmp", path);
+        let result = File::create(&tmp_path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                serde_json::to_writer_pretty(file, tokens).map_err(|e| e.to_string())
+            })
+            .and_then(|_| fs::rename(&tmp_path, path).map_err(|e| e.to_string()));
+        if let Err(err) = result {
+            error!("could not save tokens to {}: {}", path, err);
+        }
+    }
+}
+
+/// User linked through account linking, resolved from the bearer token of the request.
+///
+/// Requests without a valid, unexpired access token are rejected with 401.
+#[derive(Debug)]
+pub struct User {
+    pub id: String,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for User {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<User, ()> {
+        let oauth = match request.guard::<State<OAuth>>() {
+            Outcome::Success(oauth) => oauth,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        let header = match request.headers().get_one("Authorization") {
+            Some(header) => header,
+            None => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+        let mut parts = header.splitn(2, ' ');
+        let token = match (parts.next(), parts.next()) {
+            (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+            _ => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+        match oauth.validate_token(token) {
+            Some(id) => Outcome::Success(User { id: id }),
+            None => {
+                debug!("invalid access token");
+                Outcome::Failure((Status::Unauthorized, ()))
+            }
+        }
+    }
+}
+
 #[derive(FromForm, Debug)]
 struct AuthForm {
     response_type: Option<String>,
@@ -14,53 +511,156 @@ struct AuthForm {
     state: Option<String>,
 }
 
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn login_page(client_id: &str, redirect_uri: &str, state: &str, message: &str) -> Html<String> {
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><title>Link account</title></head>
+<body>
+<p>{}</p>
+<form method="post" action="/auth">
+<input type="hidden" name="client_id" value="{}">
+<input type="hidden" name="redirect_uri" value="{}">
+<input type="hidden" name="state" value="{}">
+<label>User <input type="text" name="username"></label>
+<label>Password <input type="password" name="password"></label>
+<input type="submit" value="Link">
+</form>
+</body>
+</html>
+"#,
+        escape(message),
+        escape(client_id),
+        escape(redirect_uri),
+        escape(state)
+    ))
+}
+
+/// Start of the authorization code flow: shows a login form to the user.
+///
+/// The client and redirect URI are validated before anything else, and errors related to them are
+/// not redirected back to the client, as per RFC 6749 section 4.1.2.1.
 #[get("/auth?<data>")]
-fn auth(data: AuthForm) -> Redirect {
+fn auth(data: AuthForm, oauth: State<OAuth>) -> Result<Html<String>, Custom<String>> {
     debug!("auth data: {:?}", data);
 
-    let s = match data.state {
-        Some(x) => x,
-        _ => "".to_string(),
-    };
+    let client_id = data.client_id.unwrap_or("".to_string());
+    let redirect_uri = data.redirect_uri.unwrap_or("".to_string());
+    if !oauth.valid_redirect_uri(&client_id, &redirect_uri) {
+        return Err(Custom(
+            Status::BadRequest,
+            "invalid client_id or redirect_uri".to_string(),
+        ));
+    }
+    if data.response_type.as_ref().map(String::as_ref) != Some("code") {
+        return Err(Custom(
+            Status::BadRequest,
+            "unsupported response_type".to_string(),
+        ));
+    }
 
-    let u = match data.redirect_uri {
-        Some(x) => {
-            let mut url = Url::parse(&x).unwrap();
-            url.set_query(Some(&format!("code=123&state={}", s)));
-            url
-        }
-        _ => Url::parse("").unwrap(),
-    };
+    Ok(login_page(
+        &client_id,
+        &redirect_uri,
+        &data.state.unwrap_or("".to_string()),
+        "Sign in to link your account.",
+    ))
+}
 
-    Redirect::found(u.as_str())
+#[derive(FromForm, Debug)]
+struct LoginForm {
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    username: String,
+    password: String,
+}
+
+/// Submission of the login form: issues an authorization code and redirects back to the client.
+#[post("/auth", data = "<data>")]
+fn auth_login(
+    data: Form<LoginForm>,
+    oauth: State<OAuth>,
+) -> Result<Redirect, Custom<Html<String>>> {
+    let data = data.into_inner();
+    debug!("auth login: {:?} {:?}", data.client_id, data.username);
+
+    if !oauth.valid_redirect_uri(&data.client_id, &data.redirect_uri) {
+        return Err(Custom(
+            Status::BadRequest,
+            Html("invalid client_id or redirect_uri".to_string()),
+        ));
+    }
+    if !oauth.authenticate_user(&data.username, &data.password) {
+        return Err(Custom(
+            Status::Unauthorized,
+            login_page(
+                &data.client_id,
+                &data.redirect_uri,
+                &data.state,
+                "Invalid user or password.",
+            ),
+        ));
+    }
+
+    let code = oauth.issue_code(&data.client_id, &data.username, &data.redirect_uri);
+    let mut url = Url::parse(&data.redirect_uri).unwrap();
+    url.query_pairs_mut()
+        .append_pair("code", &code)
+        .append_pair("state", &data.state);
+    Ok(Redirect::found(url.as_str()))
 }
 
 #[derive(FromForm, Debug)]
 struct TokenForm {
     grant_type: String,
-    code: String,
-    redirect_uri: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    refresh_token: Option<String>,
     client_id: String,
     client_secret: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Debug)]
 struct AuthResponse {
     token_type: String,
     access_token: String,
-    refresh_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
     expires_in: i64,
 }
 
 #[post("/token", data = "<data>")]
-fn token(data: Form<TokenForm>) -> Json<AuthResponse> {
-    debug!("token: {:?}", data.get());
-    Json(AuthResponse {
-        token_type: "bearer".to_string(),
-        access_token: "xxx".to_string(),
-        refresh_token: "yyy".to_string(),
-        expires_in: 1000000,
-    })
+fn token(
+    data: Form<TokenForm>,
+    oauth: State<OAuth>,
+) -> Result<Json<AuthResponse>, Custom<Json<TokenError>>> {
+    let data = data.get();
+    debug!("token: {:?} {:?}", data.grant_type, data.client_id);
+
+    if !oauth.authenticate_client(&data.client_id, &data.client_secret) {
+        return Err(Custom(
+            Status::Unauthorized,
+            Json(TokenError::new("invalid_client")),
+        ));
+    }
+
+    let result = match data.grant_type.as_ref() {
+        "authorization_code" => oauth.exchange_code(data),
+        "refresh_token" => oauth.refresh(data),
+        _ => Err(TokenError::new("unsupported_grant_type")),
+    };
+    result
+        .map(Json)
+        .map_err(|e| Custom(Status::BadRequest, Json(e)))
 }
 
 #[get("/login")]
@@ -68,3 +668,321 @@ fn login() -> String {
     debug!("login");
     "login".to_string()
 }
+
+#[cfg(test)]
+impl OAuth {
+    /// Issues an access token to the given user, as if they had just linked their account.
+    pub fn link_user(&self, user_id: &str) -> String {
+        let code = self.issue_code("google", user_id, "https://example.com/r");
+        self.exchange_code(&code_form(&code)).unwrap().access_token
+    }
+}
+
+#[cfg(test)]
+fn test_config(dir: &TempDir) -> OAuthConfig {
+    OAuthConfig {
+        clients: vec![
+            ClientConfig {
+                client_id: "google".to_string(),
+                client_secret: "secret".to_string(),
+                redirect_uris: vec!["https://example.com/r".to_string()],
+            },
+        ],
+        users: vec![
+            UserConfig {
+                id: "user".to_string(),
+                password_hash: hash_password("password"),
+            },
+        ],
+        token_store: dir.file("tokens.json"),
+        ..OAuthConfig::default()
+    }
+}
+
+#[cfg(test)]
+fn code_form(code: &str) -> TokenForm {
+    TokenForm {
+        grant_type: "authorization_code".to_string(),
+        code: Some(code.to_string()),
+        redirect_uri: Some("https://example.com/r".to_string()),
+        refresh_token: None,
+        client_id: "google".to_string(),
+        client_secret: "secret".to_string(),
+    }
+}
+
+#[cfg(test)]
+fn refresh_form(refresh_token: &str) -> TokenForm {
+    TokenForm {
+        grant_type: "refresh_token".to_string(),
+        code: None,
+        redirect_uri: None,
+        refresh_token: Some(refresh_token.to_string()),
+        client_id: "google".to_string(),
+        client_secret: "secret".to_string(),
+    }
+}
+
+#[test]
+fn test_password_hash() {
+    let hash = hash_password("password");
+    assert!(verify_password(&hash, "password"));
+    assert!(!verify_password(&hash, "Password"));
+    assert!(!verify_password(&hash, ""));
+    // Hashes are salted.
+    assert!(hash != hash_password("password"));
+
+    assert!(!verify_password("password", "password"));
+    assert!(parse_password_hash("pbkdf2-sha256$0$00$00").is_none());
+    assert!(parse_password_hash("pbkdf2-sha256$1000$00$00").is_none());
+    assert!(parse_password_hash(&hash.replace("pbkdf2-sha256", "pbkdf2-sha1")).is_none());
+
+    let config = OAuthConfig {
+        users: vec![
+            UserConfig {
+                id: "user".to_string(),
+                password_hash: "password".to_string(),
+            },
+        ],
+        ..OAuthConfig::default()
+    };
+    assert_eq!(
+        config.validate(),
+        Err("invalid password hash for oauth user \"user\"".to_string())
+    );
+}
+
+#[test]
+fn test_authenticate() {
+    let dir = TempDir::new("oauth");
+    let oauth = OAuth::new(test_config(&dir)).unwrap();
+
+    assert!(oauth.authenticate_client("google", "secret"));
+    assert!(!oauth.authenticate_client("google", "secret2"));
+    assert!(!oauth.authenticate_client("google", ""));
+    assert!(!oauth.authenticate_client("other", "secret"));
+
+    assert!(oauth.authenticate_user("user", "password"));
+    assert!(!oauth.authenticate_user("user", "secret"));
+    assert!(!oauth.authenticate_user("other", "password"));
+
+    assert!(oauth.valid_redirect_uri("google", "https://example.com/r"));
+    assert!(!oauth.valid_redirect_uri("google", "https://example.com/r/"));
+    assert!(!oauth.valid_redirect_uri("google", "https://example.com/r?x=1"));
+    assert!(!oauth.valid_redirect_uri("google", "https://evil.example.com/r"));
+    assert!(!oauth.valid_redirect_uri("other", "https://example.com/r"));
+}
+
+#[test]
+fn test_exchange_code() {
+    let dir = TempDir::new("oauth");
+    let oauth = OAuth::new(test_config(&dir)).unwrap();
+
+    let code = oauth.issue_code("google", "user", "https://example.com/r");
+    let response = oauth.exchange_code(&code_form(&code)).unwrap();
+    assert_eq!(response.token_type, "bearer");
+    assert_eq!(response.expires_in, 60 * 60);
+    assert!(response.refresh_token.is_some());
+    assert_eq!(
+        oauth.validate_token(&response.access_token),
+        Some("user".to_string())
+    );
+    // Codes can only be used once.
+    assert_eq!(
+        oauth.exchange_code(&code_form(&code)).unwrap_err().error,
+        "invalid_grant"
+    );
+
+    // Codes are bound to the client and redirect uri they were issued for, and are consumed even
+    // by failed attempts.
+    let code = oauth.issue_code("google", "user", "https://example.com/r");
+    let mut form = code_form(&code);
+    form.redirect_uri = Some("https://example.com/other".to_string());
+    assert_eq!(oauth.exchange_code(&form).unwrap_err().error, "invalid_grant");
+    assert_eq!(
+        oauth.exchange_code(&code_form(&code)).unwrap_err().error,
+        "invalid_grant"
+    );
+    let code = oauth.issue_code("google", "user", "https://example.com/r");
+    let mut form = code_form(&code);
+    form.client_id = "other".to_string();
+    assert_eq!(oauth.exchange_code(&form).unwrap_err().error, "invalid_grant");
+
+    assert_eq!(
+        oauth.exchange_code(&code_form("unknown")).unwrap_err().error,
+        "invalid_grant"
+    );
+    let mut form = code_form("");
+    form.code = None;
+    assert_eq!(oauth.exchange_code(&form).unwrap_err().error, "invalid_request");
+}
+
+#[test]
+fn test_code_expiry() {
+    let dir = TempDir::new("oauth");
+    let oauth = OAuth::new(OAuthConfig {
+        code_ttl: 0,
+        ..test_config(&dir)
+    }).unwrap();
+
+    let code = oauth.issue_code("google", "user", "https://example.com/r");
+    assert_eq!(
+        oauth.exchange_code(&code_form(&code)).unwrap_err().error,
+        "invalid_grant"
+    );
+}
+
+#[test]
+fn test_refresh() {
+    let dir = TempDir::new("oauth");
+    let oauth = OAuth::new(test_config(&dir)).unwrap();
+
+    let code = oauth.issue_code("google", "user", "https://example.com/r");
+    let response = oauth.exchange_code(&code_form(&code)).unwrap();
+    let refresh_token = response.refresh_token.unwrap();
+
+    let refreshed = oauth.refresh(&refresh_form(&refresh_token)).unwrap();
+    assert!(refreshed.refresh_token.is_none());
+    assert!(refreshed.access_token != response.access_token);
+    assert_eq!(
+        oauth.validate_token(&refreshed.access_token),
+        Some("user".to_string())
+    );
+    // Refresh tokens can be used repeatedly.
+    assert!(oauth.refresh(&refresh_form(&refresh_token)).is_ok());
+
+    let mut form = refresh_form(&refresh_token);
+    form.client_id = "other".to_string();
+    assert_eq!(oauth.refresh(&form).unwrap_err().error, "invalid_grant");
+    assert_eq!(
+        oauth.refresh(&refresh_form("unknown")).unwrap_err().error,
+        "invalid_grant"
+    );
+    let mut form = refresh_form("");
+    form.refresh_token = None;
+    assert_eq!(oauth.refresh(&form).unwrap_err().error, "invalid_request");
+}
+
+#[test]
+fn test_refresh_expiry() {
+    let dir = TempDir::new("oauth");
+    let oauth = OAuth::new(OAuthConfig {
+        refresh_token_ttl: 0,
+        ..test_config(&dir)
+    }).unwrap();
+
+    let code = oauth.issue_code("google", "user", "https://example.com/r");
+    let refresh_token = oauth
+        .exchange_code(&code_form(&code))
+        .unwrap()
+        .refresh_token
+        .unwrap();
+    assert_eq!(
+        oauth.refresh(&refresh_form(&refresh_token)).unwrap_err().error,
+        "invalid_grant"
+    );
+    assert!(oauth.linked_users().is_empty());
+}
+
+#[test]
+fn test_token_store() {
+    let dir = TempDir::new("oauth");
+    let (code, response) = {
+        let oauth = OAuth::new(test_config(&dir)).unwrap();
+        let code = oauth.issue_code("google", "user", "https://example.com/r");
+        let response = oauth.exchange_code(&code_form(&code)).unwrap();
+        let code = oauth.issue_code("google", "user", "https://example.com/r");
+        (code, response)
+    };
+
+    let oauth = OAuth::new(test_config(&dir)).unwrap();
+    assert_eq!(
+        oauth.validate_token(&response.access_token),
+        Some("user".to_string())
+    );
+    assert_eq!(oauth.linked_users(), btreeset!{"user".to_string()});
+    assert!(
+        oauth
+            .refresh(&refresh_form(&response.refresh_token.unwrap()))
+            .is_ok()
+    );
+    // Codes are not persisted.
+    assert_eq!(
+        oauth.exchange_code(&code_form(&code)).unwrap_err().error,
+        "invalid_grant"
+    );
+}
+
+#[cfg(test)]
+#[get("/user")]
+fn user_id(user: User) -> String {
+    user.id
+}
+
+#[test]
+fn test_user_guard() {
+    use rocket::http::Header;
+    use rocket::local::Client;
+
+    let dir = TempDir::new("oauth");
+    let oauth = OAuth::new(test_config(&dir)).unwrap();
+    let code = oauth.issue_code("google", "user", "https://example.com/r");
+    let access_token = oauth.exchange_code(&code_form(&code)).unwrap().access_token;
+    oauth.state.lock().unwrap().tokens.access_tokens.insert(
+        "expired".to_string(),
+        Grant {
+            client_id: "google".to_string(),
+            user_id: "user".to_string(),
+            expires: Some(now() - 1),
+        },
+    );
+
+    assert_eq!(oauth.validate_token(&access_token), Some("user".to_string()));
+    assert_eq!(oauth.validate_token("expired"), None);
+    assert_eq!(oauth.validate_token("unknown"), None);
+    assert_eq!(oauth.validate_token(""), None);
+
+    let client = Client::new(rocket::ignite().manage(oauth).mount("/", routes![user_id])).unwrap();
+    let get = |authorization: &str| {
+        let mut response = client
+            .get("/user")
+            .header(Header::new("Authorization", authorization.to_string()))
+            .dispatch();
+        (response.status(), response.body_string())
+    };
+
+    let bearer = format!("Bearer {}", access_token);
+    assert_eq!(get(&bearer), (Status::Ok, Some("user".to_string())));
+    assert_eq!(
+        get(&format!("bearer {}", access_token)),
+        (Status::Ok, Some("user".to_string()))
+    );
+    assert_eq!(get(&format!("Basic {}", access_token)).0, Status::Unauthorized);
+    assert_eq!(get(&access_token).0, Status::Unauthorized);
+    assert_eq!(get("Bearer expired").0, Status::Unauthorized);
+    assert_eq!(get("Bearer unknown").0, Status::Unauthorized);
+    assert_eq!(client.get("/user").dispatch().status(), Status::Unauthorized);
+}
+
+#[test]
+fn test_revoke_user() {
+    let dir = TempDir::new("oauth");
+    let oauth = OAuth::new(test_config(&dir)).unwrap();
+    let token = oauth.link_user("user");
+    let other_token = oauth.link_user("other");
+    let code = oauth.issue_code("google", "user", "https://example.com/r");
+
+    oauth.revoke_user("user");
+    assert_eq!(oauth.validate_token(&token), None);
+    assert_eq!(oauth.validate_token(&other_token), Some("other".to_string()));
+    assert_eq!(oauth.linked_users(), btreeset!{"other".to_string()});
+    assert_eq!(
+        oauth.exchange_code(&code_form(&code)).unwrap_err().error,
+        "invalid_grant"
+    );
+
+    // Revocation survives a restart.
+    let oauth = OAuth::new(test_config(&dir)).unwrap();
+    assert_eq!(oauth.validate_token(&token), None);
+    assert_eq!(oauth.linked_users(), btreeset!{"other".to_string()});
+}
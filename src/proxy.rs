@@ -1,54 +1,189 @@
-use device::Device;
-use futures::Future;
-use futures::stream::Stream;
-use google_actions::{ActionRequest, ActionRequestInput, ExecuteResponseCommand, Name, Params,
-                     SyncResponseDevice, SyncResponseDeviceAttributes};
-use hyper::{Client, Method, Request};
-use tokio_core::reactor::Core;
-use serde_json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
 
+use reqwest;
+use reqwest::header::{Authorization, Bearer};
+use serde::de::DeserializeOwned;
+
+use google_actions::{ActionRequest, ActionRequestInput, ActionRequestPayload, Command,
+                     ExecuteResponse, ExecuteResponseCommand, Params, QueryResponse,
+                     RequestDevice, SyncResponse, SyncResponseDevice};
+
+/// A downstream hub whose devices are exposed as if they were part of this one.
+///
+/// The downstream hub must speak the same fulfillment protocol as the `/action` endpoint (e.g.
+/// another instance of this server).
 pub struct Proxy {
-    pub id: String,
-    pub target_url: String,
+    pub url: String,
+    /// Access token presented to the downstream hub, if it requires one.
+    token: Option<String>,
+    /// Ids of the devices returned by the last successful SYNC of the downstream hub.
+    devices: Mutex<BTreeSet<String>>,
 }
 
-impl Device for Proxy {
-    fn id(&self) -> String {
-        self.id.clone()
+impl Proxy {
+    pub fn new(url: &str, token: Option<String>) -> Proxy {
+        Proxy {
+            url: url.to_string(),
+            token: token,
+            devices: Mutex::new(BTreeSet::new()),
+        }
     }
 
-    fn sync(&self) -> Option<SyncResponseDevice> {
-        let mut core = Core::new().unwrap();
-        let client = Client::new(&core.handle());
-        let uri = self.target_url.parse().unwrap();
+    /// Whether the device with the given id is owned by the downstream hub, as of the last SYNC.
+    pub fn owns(&self, id: &str) -> bool {
+        self.devices.lock().unwrap().contains(id)
+    }
 
-        let mut req = Request::new(Method::Post, uri);
-        let action = ActionRequest {
-            request_id: "lksdjflksdjkl".to_string(),
+    pub fn sync(&self, request_id: &str) -> Result<Vec<SyncResponseDevice>, String> {
+        let response: SyncResponse = self.send(&ActionRequest {
+            request_id: request_id.to_string(),
             inputs: vec![
                 ActionRequestInput {
                     intent: "action.devices.SYNC".to_string(),
-                    payload: Option::None,
+                    payload: None,
                 },
             ],
-        };
-        req.set_body(serde_json::to_string(&action).unwrap());
-
-        let work = client.request(req).and_then(|res| {
-            res.body().concat2().and_then(move |body| {
-                let r = serde_json::from_slice::<SyncResponseDevice>(&body).unwrap();
-                Ok(r)
-            })
-        });
-        let res = core.run(work).unwrap();
-        Option::None
+        })?;
+        *self.devices.lock().unwrap() = response
+            .payload
+            .devices
+            .iter()
+            .map(|d| d.id.clone())
+            .collect();
+        Ok(response.payload.devices)
     }
 
-    fn query(&self) -> Option<Params> {
-        Option::None
+    /// Syncs the downstream hub again, returning whether its set of devices changed.
+    pub fn refresh(&self, request_id: &str) -> Result<bool, String> {
+        let before = self.devices.lock().unwrap().clone();
+        self.sync(request_id)?;
+        Ok(*self.devices.lock().unwrap() != before)
+    }
+
+    pub fn query(
+        &self,
+        request_id: &str,
+        devices: Vec<RequestDevice>,
+    ) -> Result<BTreeMap<String, Params>, String> {
+        let response: QueryResponse = self.send(&ActionRequest {
+            request_id: request_id.to_string(),
+            inputs: vec![
+                ActionRequestInput {
+                    intent: "action.devices.QUERY".to_string(),
+                    payload: Some(ActionRequestPayload {
+                        devices: devices,
+                        commands: vec![],
+                    }),
+                },
+            ],
+        })?;
+        Ok(response.payload.devices)
+    }
+
+    pub fn execute(
+        &self,
+        request_id: &str,
+        commands: Vec<Command>,
+    ) -> Result<Vec<ExecuteResponseCommand>, String> {
+        let response: ExecuteResponse = self.send(&ActionRequest {
+            request_id: request_id.to_string(),
+            inputs: vec![
+                ActionRequestInput {
+                    intent: "action.devices.EXECUTE".to_string(),
+                    payload: Some(ActionRequestPayload {
+                        devices: vec![],
+                        commands: commands,
+                    }),
+                },
+            ],
+        })?;
+        Ok(response.payload.commands)
     }
 
-    fn execute(&mut self, params: &Params) -> Option<ExecuteResponseCommand> {
-        Option::None
+    fn send<T: DeserializeOwned>(&self, request: &ActionRequest) -> Result<T, String> {
+        debug!("proxy request to {}: {:?}", self.url, request);
+        let client = reqwest::Client::new();
+        let mut builder = client.post(&self.url);
+        builder.json(request);
+        if let Some(ref token) = self.token {
+            builder.header(Authorization(Bearer {
+                token: token.clone(),
+            }));
+        }
+        let mut res = builder
+            .send()
+            .map_err(|e| format!("could not send request to {}: {}", self.url, e))?;
+        debug!("proxy response: {:?}", res);
+        if !res.status().is_success() {
+            return Err(format!("{} returned {}", self.url, res.status()));
+        }
+        res.json::<T>()
+            .map_err(|e| format!("could not parse response from {}: {}", self.url, e))
     }
 }
+
+/// Starts a downstream hub owning a single light, which fails every QUERY and EXECUTE unless
+/// `online`.
+#[cfg(test)]
+pub fn mock_hub(id: &'static str, online: bool) -> (String, ::std::sync::mpsc::Receiver<String>) {
+    use testing::mock_server;
+
+    mock_server(move |body| {
+        let response = if body.contains("action.devices.SYNC") {
+            r#"{"requestId": "1", "payload": {"agentUserId": "user", "devices": [{
+                "id": "ID", "type": "action.devices.types.LIGHT", "name": {"name": "Porch"},
+                "traits": ["action.devices.traits.OnOff"], "willReportState": false
+            }]}}"#
+        } else if !online {
+            return None;
+        } else if body.contains("action.devices.QUERY") {
+            r#"{"requestId": "1", "payload": {"devices": {"ID": {"on": true}}}}"#
+        } else {
+            r#"{"requestId": "1", "payload": {"commands": [
+                {"ids": ["ID"], "status": "SUCCESS", "states": {"on": false}}
+            ]}}"#
+        };
+        Some(response.replace("ID", id))
+    })
+}
+
+#[test]
+fn test_proxy() {
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    let (url, requests) = mock_hub("p1", true);
+    let proxy = Proxy::new(&url, Some("secret".to_string()));
+    assert!(!proxy.owns("p1"));
+    let devices = proxy.sync("1").unwrap();
+    assert_eq!(vec!["p1"], devices.iter().map(|d| d.id.as_str()).collect::<Vec<_>>());
+    assert!(proxy.owns("p1"));
+    let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request.contains("Authorization: Bearer secret"));
+    assert_eq!(Ok(false), proxy.refresh("2"));
+
+    let p1 = || vec![RequestDevice { id: "p1".to_string() }];
+    assert_eq!(Some(true), proxy.query("3", p1()).unwrap()["p1"].on);
+    let command = Command {
+        devices: p1(),
+        execution: vec![],
+    };
+    let commands = proxy.execute("4", vec![command]).unwrap();
+    assert_eq!(vec!["p1".to_string()], commands[0].ids);
+
+    let (url, _) = mock_hub("p2", false);
+    let failing = Proxy::new(&url, None);
+    assert!(failing.sync("5").is_ok());
+    let p2 = vec![RequestDevice { id: "p2".to_string() }];
+    assert!(failing.query("6", p2).is_err());
+
+    // Find a free port, and close it.
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let offline = Proxy::new(&format!("http://{}", address), None);
+    assert!(offline.sync("7").is_err());
+    assert!(!offline.owns("p1"));
+}
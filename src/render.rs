@@ -0,0 +1,254 @@
+extern crate rgb;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color::{Frame, BLACK};
+use led::{LedOutput, OutputConfig};
+use light::Light;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RenderConfig {
+    /// Number of frames rendered per second.
+    #[serde(default = "default_frame_rate")]
+    pub frame_rate: u32,
+    /// Number of pixels on each output channel.
+    #[serde(default = "default_channels")]
+    pub channels: Vec<usize>,
+    /// Outputs to which every frame is sent.
+    #[serde(default = "default_outputs")]
+    pub outputs: Vec<OutputConfig>,
+}
+
+fn default_frame_rate() -> u32 {
+    100
+}
+
+/// The 4 channels of the Mote, each with a 16 pixel stick.
+fn default_channels() -> Vec<usize> {
+    vec![16; 4]
+}
+
+fn default_outputs() -> Vec<OutputConfig> {
+    vec![OutputConfig::Mote]
+}
+
+impl Default for RenderConfig {
+    fn default() -> RenderConfig {
+        RenderConfig {
+            frame_rate: default_frame_rate(),
+            channels: default_channels(),
+            outputs: default_outputs(),
+        }
+    }
+}
+
+impl RenderConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.frame_rate == 0 || self.frame_rate > 1000 {
+            return Err("frame_rate must be between 1 and 1000".to_string());
+        }
+        for output in &self.outputs {
+            output.validate(&self.channels)?;
+        }
+        Ok(())
+    }
+}
+
+/// Interval at which frame statistics are logged.
+const STATS_INTERVAL_SECS: u64 = 60;
+
+/// Paces rendering at a fixed frame rate, keeping track of the frames that could not be rendered
+/// in time.
+pub struct FrameClock {
+    interval: Duration,
+    start: Instant,
+    last: Instant,
+    /// Time at which the next frame is due.
+    next: Instant,
+    rendered: u64,
+    dropped: u64,
+    stats_start: Instant,
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
+
+impl FrameClock {
+    pub fn new(frame_rate: u32, now: Instant) -> FrameClock {
+        FrameClock {
+            interval: Duration::from_secs(1) / frame_rate,
+            start: now,
+            last: now,
+            next: now,
+            rendered: 0,
+            dropped: 0,
+            stats_start: now,
+        }
+    }
+
+    /// Returns the timing of a frame rendered now.
+    pub fn frame(&mut self, now: Instant) -> Frame {
+        let frame = Frame {
+            elapsed: now.duration_since(self.start),
+            delta: now.duration_since(self.last),
+        };
+        self.last = now;
+        self.rendered += 1;
+        frame
+    }
+
+    /// Returns how long to wait for the next frame to be due.
+    ///
+    /// If rendering took longer than a frame, the frames whose time has passed are dropped rather
+    /// than rendered late, so that frames stay evenly spaced.
+    pub fn wait(&mut self, now: Instant) -> Duration {
+        self.next += self.interval;
+        if now > self.next {
+            let missed = nanos(now.duration_since(self.next)) / nanos(self.interval) + 1;
+            self.dropped += missed;
+            self.next += self.interval * missed as u32;
+        }
+        self.next.duration_since(now)
+    }
+
+    /// Returns the numbers of frames rendered and dropped since the last call, once every
+    /// `interval`.
+    pub fn stats(&mut self, now: Instant, interval: Duration) -> Option<(u64, u64)> {
+        if now.duration_since(self.stats_start) < interval {
+            return None;
+        }
+        let stats = (self.rendered, self.dropped);
+        self.rendered = 0;
+        self.dropped = 0;
+        self.stats_start = now;
+        Some(stats)
+    }
+}
+
+/// Renders a frame of a light, whose pixels may be spread over several channels.
+fn update_light(channels: &mut [Vec<rgb::RGB8>], frame: &Frame, light: &Arc<Mutex<Light>>) {
+    match light.lock() {
+        Ok(light) => {
+            let mut pixels = vec![];
+            for r in &light.pixels {
+                pixels.extend_from_slice(&channels[r.channel][r.offset..r.offset + r.count]);
+            }
+            light.color_func.step(frame, &mut pixels);
+            let mut rest = &pixels[..];
+            for r in &light.pixels {
+                channels[r.channel][r.offset..r.offset + r.count].copy_from_slice(&rest[..r.count]);
+                rest = &rest[r.count..];
+            }
+        }
+        Err(err) => error!("could not lock light mutex: {:?}", err),
+    }
+}
+
+/// Renders the lights to the outputs forever.
+pub fn run(
+    mut outputs: Vec<Box<LedOutput>>,
+    lights: Vec<Arc<Mutex<Light>>>,
+    config: RenderConfig,
+) {
+    let mut channels: Vec<Vec<rgb::RGB8>> =
+        config.channels.iter().map(|&count| vec![BLACK; count]).collect();
+    let mut clock = FrameClock::new(config.frame_rate, Instant::now());
+    let stats_interval = Duration::from_secs(STATS_INTERVAL_SECS);
+    loop {
+        let frame = clock.frame(Instant::now());
+        for light in &lights {
+            update_light(&mut channels, &frame, light);
+        }
+        for output in &mut outputs {
+            if let Err(err) = output.write(&channels) {
+                error!("could not write frame: {}", err);
+            }
+        }
+
+        let now = Instant::now();
+        if let Some((rendered, dropped)) = clock.stats(now, stats_interval) {
+            if dropped > 0 {
+                warn!("rendered {} frames, dropped {}", rendered, dropped);
+            } else {
+                debug!("rendered {} frames", rendered);
+            }
+        }
+        thread::sleep(clock.wait(now));
+    }
+}
+
+#[test]
+fn test_frame_clock() {
+    let t0 = Instant::now();
+    let ms = |n| t0 + Duration::from_millis(n);
+    let mut clock = FrameClock::new(100, t0);
+
+    assert_eq!(Duration::from_millis(0), clock.frame(t0).elapsed);
+    assert_eq!(Duration::from_millis(8), clock.wait(ms(2)));
+    let frame = clock.frame(ms(10));
+    assert_eq!(Duration::from_millis(10), frame.elapsed);
+    assert_eq!(Duration::from_millis(10), frame.delta);
+
+    // Rendering took 25 ms: the frames due at 20 and 30 ms are dropped.
+    assert_eq!(Duration::from_millis(5), clock.wait(ms(35)));
+    let frame = clock.frame(ms(40));
+    assert_eq!(Duration::from_millis(40), frame.elapsed);
+    assert_eq!(Duration::from_millis(30), frame.delta);
+    // Frames due right away are not dropped.
+    assert_eq!(Duration::from_millis(0), clock.wait(ms(50)));
+
+    assert_eq!(None, clock.stats(ms(40), Duration::from_secs(1)));
+    assert_eq!(Some((3, 2)), clock.stats(ms(1000), Duration::from_secs(1)));
+    assert_eq!(Some((0, 0)), clock.stats(ms(2000), Duration::from_secs(1)));
+}
+
+#[test]
+fn test_update_light() {
+    use color::{GREEN, RED, WHITE};
+    use effect::{Direction, Effect, EffectName};
+    use testing::build_hub;
+
+    let hub = build_hub(
+        r#"
+{
+  "lights": [{
+    "id": "111",
+    "name": "Bedroom lights",
+    "traits": ["OnOff"],
+    "pixels": [
+      { "channel": 0, "offset": 2, "count": 2 },
+      { "channel": 1, "offset": 0, "count": 2 }
+    ]
+  }, {
+    "id": "222",
+    "name": "Kitchen lights",
+    "traits": ["OnOff"],
+    "pixels": [{ "channel": 0, "offset": 0, "count": 2 }]
+  }],
+  "render": { "channels": [4, 3] }
+}
+"#,
+    );
+    hub.lights[0].lock().unwrap().set_effect(Effect {
+        name: EffectName::ItalianFlag,
+        period_ms: 1000,
+        palette: vec![],
+        direction: Direction::Forward,
+    });
+
+    let mut channels = vec![vec![BLACK; 4], vec![BLACK; 3]];
+    let frame = Frame {
+        elapsed: Duration::from_millis(0),
+        delta: Duration::from_millis(10),
+    };
+    for light in &hub.lights {
+        update_light(&mut channels, &frame, light);
+    }
+    assert_eq!(
+        vec![vec![BLACK, BLACK, GREEN, GREEN], vec![WHITE, RED, BLACK]],
+        channels
+    );
+}
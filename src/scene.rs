@@ -1,67 +1,191 @@
 use color;
+use effect;
 use light;
 
-use device::Device;
-use google_actions::{ExecuteResponseCommand, Name, Params, SyncResponseDevice,
+use device::{Device, Metadata};
+use google_actions::{DeviceCommand, ErrorCode, Params, SyncResponseDevice,
                      SyncResponseDeviceAttributes};
+use serde_json;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
+/// State that a scene puts a light into; unset fields are left unchanged.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LightTarget {
+    /// Id of the light.
+    pub id: String,
+    #[serde(default)]
+    pub on: Option<bool>,
+    #[serde(default)]
+    pub brightness: Option<u8>,
+    /// Color name or `#RRGGBB`.
+    #[serde(default)]
+    pub color: Option<String>,
+    /// Animated effect, replacing the color.
+    #[serde(default)]
+    #[serde(deserialize_with = "effect::deserialize_optional")]
+    pub effect: Option<effect::Effect>,
+}
+
+impl LightTarget {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.brightness.map_or(false, |b| b > 100) {
+            return Err(format!("invalid brightness for light {:?}", self.id));
+        }
+        if let Some(ref c) = self.color {
+            color::parse(c).ok_or(format!("invalid color {:?} for light {:?}", c, self.id))?;
+        }
+        if let Some(ref effect) = self.effect {
+            effect
+                .validate()
+                .map_err(|e| format!("{} for light {:?}", e, self.id))?;
+        }
+        Ok(())
+    }
+
+    fn apply(&self, l: &mut light::Light) {
+        if self.on == Some(false) {
+            l.set_on(false);
+            return;
+        }
+        if let Some(b) = self.brightness {
+            l.set_brightness(b);
+        }
+        if let Some(c) = self.color.as_ref().and_then(|c| color::parse(c)) {
+            l.set_color(c);
+        }
+        if self.on == Some(true) {
+            l.set_on(true);
+        }
+        if let Some(ref effect) = self.effect {
+            l.set_effect(effect.clone());
+        }
+    }
+}
+
+/// State of a light before a scene was activated.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LightSnapshot {
+    pub id: String,
+    pub state: light::LightStatus,
+    #[serde(default)]
+    #[serde(deserialize_with = "effect::deserialize_optional")]
+    pub effect: Option<effect::Effect>,
+}
+
+/// Snapshots of the lights affected by each active reversible scene, by scene id, persisted so
+/// that scenes can still be deactivated after a restart.
+pub struct SceneStore {
+    path: String,
+    snapshots: Mutex<HashMap<String, Vec<LightSnapshot>>>,
+}
+
+impl SceneStore {
+    /// Loads the snapshots saved at the given path, if any.
+    ///
+    /// A store that cannot be read is discarded, as this only prevents deactivating scenes.
+    pub fn load(path: &str) -> SceneStore {
+        let snapshots = if Path::new(path).exists() {
+            File::open(path)
+                .map_err(|e| format!("could not open {}: {}", path, e))
+                .and_then(|file| {
+                    serde_json::from_reader(file)
+                        .map_err(|e| format!("could not parse {}: {}", path, e))
+                })
+                .unwrap_or_else(|err| {
+                    error!("{}", err);
+                    HashMap::new()
+                })
+        } else {
+            HashMap::new()
+        };
+        SceneStore {
+            path: path.to_string(),
+            snapshots: Mutex::new(snapshots),
+        }
+    }
+
+    fn save(&self, snapshots: &HashMap<String, Vec<LightSnapshot>>) {
+        let tmp_path = format!("{}.tmp", self.path);
+        let result = File::create(&tmp_path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                serde_json::to_writer_pretty(file, snapshots).map_err(|e| e.to_string())
+            })
+            .and_then(|_| fs::rename(&tmp_path, &self.path).map_err(|e| e.to_string()));
+        if let Err(err) = result {
+            error!("could not save scenes to {}: {}", self.path, err);
+        }
+    }
+}
+
 pub struct Scene {
     pub id: String,
     pub name: String,
+    pub metadata: Metadata,
     pub reversible: bool,
-    pub lights: Vec<Arc<Mutex<light::Light>>>,
+    pub lights: Vec<(Arc<Mutex<light::Light>>, LightTarget)>,
+    pub store: Arc<SceneStore>,
 }
 
 impl Scene {
-    pub fn activate_scene(&mut self, deactivate: bool) {
+    pub fn activate_scene(&mut self, deactivate: bool) -> Result<(), ErrorCode> {
         debug!("activate_scene: {:?}", deactivate);
-        match self.name.as_ref() {
-            "Party Mode" => {
-                info!("Party Mode");
-                for light in &self.lights {
-                    let mut l = light.lock().unwrap();
-                    l.color_func = Box::new(color::Rainbow { period: 1 });
-                }
-            }
-            "Italian Mode" => {
-                info!("Italian Mode");
-                for light in &self.lights {
-                    let mut l = light.lock().unwrap();
-                    l.color_func = Box::new(color::ItalianFlag {});
-                }
-            }
-            "Strobe Mode" => {
-                info!("Strobe Mode");
-                for light in &self.lights {
-                    let mut l = light.lock().unwrap();
-                    l.color_func = Box::new(color::Strobe { period: 1 });
-                }
-            }
-            "Night Mode" => {
-                info!("Night Mode");
-                for light in &self.lights {
-                    let mut l = light.lock().unwrap();
-                    match l.name.as_ref() {
-                        "Bedroom lights" => {
-                            l.set_on(false);
-                        }
-                        "Kitchen lights" => {
-                            l.set_on(false);
+        if deactivate {
+            return self.deactivate_scene();
+        }
+        info!("activating scene {:?}", self.name);
+        if self.reversible {
+            let mut snapshots = self.store.snapshots.lock().unwrap();
+            // If the scene is activated again, keep the state from before the first activation.
+            if !snapshots.contains_key(&self.id) {
+                let snapshot = self.lights
+                    .iter()
+                    .map(|&(ref light, _)| {
+                        let l = light.lock().unwrap();
+                        LightSnapshot {
+                            id: l.id.clone(),
+                            state: l.status.clone(),
+                            effect: l.effect.clone(),
                         }
-                        "Bathroom lights" => {
-                            l.set_color(color::RED);
-                            l.set_brightness(10);
-                        }
-                        "Living room lights" => {
-                            l.set_on(false);
-                        }
-                        _ => {}
-                    }
-                }
+                    })
+                    .collect();
+                snapshots.insert(self.id.clone(), snapshot);
+                self.store.save(&snapshots);
+            }
+        }
+        for &(ref light, ref target) in &self.lights {
+            target.apply(&mut light.lock().unwrap());
+        }
+        Ok(())
+    }
+
+    fn deactivate_scene(&mut self) -> Result<(), ErrorCode> {
+        if !self.reversible {
+            return Err(ErrorCode::NotSupported);
+        }
+        info!("deactivating scene {:?}", self.name);
+        let mut snapshots = self.store.snapshots.lock().unwrap();
+        let snapshot = match snapshots.remove(&self.id) {
+            Some(snapshot) => snapshot,
+            None => {
+                debug!("scene {:?} is not active", self.id);
+                return Ok(());
             }
-            _ => {}
         };
+        for s in snapshot {
+            if let Some(&(ref light, _)) = self.lights
+                .iter()
+                .find(|&&(ref l, _)| l.lock().unwrap().id == s.id)
+            {
+                light.lock().unwrap().restore(s.state, s.effect);
+            }
+        }
+        self.store.save(&snapshots);
+        Ok(())
     }
 }
 
@@ -70,37 +194,93 @@ impl Device for Scene {
         self.id.clone()
     }
 
-    fn sync(&self) -> Option<SyncResponseDevice> {
-        Option::Some(SyncResponseDevice {
+    fn sync(&self) -> SyncResponseDevice {
+        SyncResponseDevice {
             id: self.id(),
             type_: "action.devices.types.SCENE".to_string(),
             traits: vec!["action.devices.traits.Scene".to_string()],
-            name: Name {
-                default_name: vec![self.name.to_string()],
-                name: Some(self.name.clone()),
-                nicknames: vec![],
-            },
+            name: self.metadata.name(&self.name),
             will_report_state: false,
-            device_info: None,
-            room_hint: None,
-            structure_hint: None,
+            device_info: self.metadata.device_info.clone(),
+            room_hint: self.metadata.room_hint.clone(),
+            structure_hint: self.metadata.structure_hint.clone(),
             attributes: Some(SyncResponseDeviceAttributes {
                 scene_reversible: Some(self.reversible),
                 ..SyncResponseDeviceAttributes::default()
             }),
+        }
+    }
+
+    fn query(&self) -> Result<Params, ErrorCode> {
+        // Scenes are stateless.
+        Ok(Params {
+            online: Some(true),
+            ..Params::default()
         })
     }
 
-    fn query(&self) -> Option<Params> {
-        Option::None
+    fn execute(&mut self, command: &DeviceCommand) -> Result<Params, ErrorCode> {
+        match command {
+            &DeviceCommand::ActivateScene { deactivate } => self.activate_scene(deactivate)?,
+            _ => return Err(ErrorCode::NotSupported),
+        }
+        self.query()
     }
+}
 
-    fn execute(&mut self, params: &Params) -> Option<ExecuteResponseCommand> {
-        self.activate_scene(params.deactivate.unwrap_or(false));
-        Option::Some(ExecuteResponseCommand {
-            ids: vec![self.id()],
-            status: "SUCCESS".to_string(),
-            states: Params::default(),
-        })
+#[test]
+fn test_reversible_scene() {
+    use testing::{build_hub, TempDir};
+
+    let dir = TempDir::new("scenes");
+    let config = format!(
+        r#"
+{{
+  "lights": [{{
+    "id": "111",
+    "name": "Bedroom lights",
+    "traits": ["OnOff", "Brightness", "ColorSpectrum"],
+    "pixels": [{{ "channel": 0, "offset": 0, "count": 16 }}]
+  }}],
+  "scenes": [{{
+    "id": "1001",
+    "name": "Party Mode",
+    "reversible": true,
+    "lights": [{{ "id": "111", "color": "red", "effect": {{ "name": "rainbow" }} }}]
+  }}],
+  "scene_store": {:?}
+}}
+"#,
+        dir.file("scenes.json")
+    );
+    let hub = build_hub(&config);
+    let activate = |hub: &::hub::Hub, deactivate| {
+        hub.device("1001")
+            .unwrap()
+            .lock()
+            .unwrap()
+            .execute(&DeviceCommand::ActivateScene {
+                deactivate: deactivate,
+            })
+    };
+    hub.lights[0].lock().unwrap().set_color(color::BLUE);
+
+    activate(&hub, false).unwrap();
+    activate(&hub, false).unwrap();
+    {
+        let light = hub.lights[0].lock().unwrap();
+        assert_eq!(light::LightColor::Spectrum(color::RED), light.status.color);
+        assert_eq!(
+            Some(&effect::EffectName::Rainbow),
+            light.effect.as_ref().map(|e| &e.name)
+        );
     }
+
+    // The snapshot survives a restart.
+    let hub = build_hub(&config);
+    activate(&hub, true).unwrap();
+    let light = hub.lights[0].lock().unwrap();
+    assert_eq!(light::LightColor::Spectrum(color::BLUE), light.status.color);
+    assert!(light.status.on);
+    assert_eq!(None, light.effect);
 }
@@ -0,0 +1,322 @@
+use rocket::State;
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket_contrib::Json;
+use serde_json;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+use time;
+
+use hub::Hub;
+use oauth::User;
+use thermostat::SetpointLimits;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+
+/// A setpoint that takes effect at a given time on some days of the week, e.g.
+/// `{"days": "weekdays", "time": "06:30", "setpoint": 21}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Slot {
+    /// `daily`, `weekdays`, `weekends` or a comma-separated list of days (`mon`, `tue`, ...).
+    pub days: String,
+    /// Local time, as `HH:MM`.
+    pub time: String,
+    /// Setpoint in Celsius.
+    pub setpoint: f32,
+}
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+impl Slot {
+    /// Returns the minutes since Monday 00:00 at which the slot starts, once for each of its days.
+    fn starts(&self) -> Result<Vec<u32>, String> {
+        let days: Vec<u32> = match self.days.as_ref() {
+            "daily" => (0..7).collect(),
+            "weekdays" => (0..5).collect(),
+            "weekends" => (5..7).collect(),
+            days => days.split(',')
+                .map(|d| {
+                    DAYS.iter()
+                        .position(|n| *n == d.trim())
+                        .map(|i| i as u32)
+                        .ok_or(format!("invalid day {:?}", d))
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        let mut parts = self.time.splitn(2, ':');
+        let minute = match (
+            parts.next().and_then(|h| h.parse::<u32>().ok()),
+            parts.next().and_then(|m| m.parse::<u32>().ok()),
+        ) {
+            (Some(h), Some(m)) if h < 24 && m < 60 => h * 60 + m,
+            _ => return Err(format!("invalid time {:?}", self.time)),
+        };
+        Ok(days.iter().map(|d| d * MINUTES_PER_DAY + minute).collect())
+    }
+}
+
+/// Weekly schedule of setpoints.
+///
+/// A slot only takes effect when it starts, so that a setpoint changed manually in the meantime
+/// is kept until the next slot, like on commercial thermostats.
+#[derive(Default)]
+pub struct Schedule {
+    slots: Vec<Slot>,
+    /// Start (in minutes since Monday 00:00) and setpoint of every occurrence of each slot,
+    /// sorted by start.
+    starts: Vec<(u32, f32)>,
+    /// Minute of the week at which the schedule was last evaluated, if any.
+    last: Option<u32>,
+    overridden: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ScheduleState {
+    pub slots: Vec<Slot>,
+    /// Whether the setpoint was changed manually since the current slot started.
+    pub overridden: bool,
+}
+
+impl Schedule {
+    pub fn new(slots: Vec<Slot>) -> Result<Schedule, String> {
+        let mut starts = vec![];
+        for slot in &slots {
+            for start in slot.starts()? {
+                if starts.iter().any(|&(s, _)| s == start) {
+                    return Err(format!("more than one slot starts at {:?}", slot.time));
+                }
+                starts.push((start, slot.setpoint));
+            }
+        }
+        starts.sort_by_key(|&(s, _)| s);
+        Ok(Schedule {
+            slots: slots,
+            starts: starts,
+            last: None,
+            overridden: false,
+        })
+    }
+
+    /// Builds a schedule whose setpoints must be accepted by a thermostat.
+    pub fn with_limits(slots: Vec<Slot>, limits: &SetpointLimits) -> Result<Schedule, String> {
+        if slots.iter().any(|s| limits.check(s.setpoint).is_err()) {
+            return Err("setpoint outside the allowed range".to_string());
+        }
+        Schedule::new(slots)
+    }
+
+    pub fn state(&self) -> ScheduleState {
+        ScheduleState {
+            slots: self.slots.clone(),
+            overridden: self.overridden,
+        }
+    }
+
+    /// Records that the setpoint was changed manually.
+    pub fn set_overridden(&mut self) {
+        if !self.starts.is_empty() {
+            self.overridden = true;
+        }
+    }
+
+    /// Evaluates the schedule at the given minute of the week, returning the setpoint to apply if
+    /// a new slot started since the last evaluation.
+    ///
+    /// Only the last of the slots that started since then takes effect, so that slots falling in a
+    /// gap (e.g. when DST starts) still do. When the clock goes back (e.g. when DST ends), slots
+    /// are not applied again until it catches up with the last evaluation; the schedule must
+    /// therefore be evaluated at least twice a week.
+    pub fn update(&mut self, minute_of_week: u32) -> Option<f32> {
+        let since = |start: u32| (minute_of_week + MINUTES_PER_WEEK - start) % MINUTES_PER_WEEK;
+        let elapsed = match self.last {
+            // On the first evaluation, the slot in effect is the last one that started.
+            None => MINUTES_PER_WEEK,
+            Some(last) if since(last) > MINUTES_PER_WEEK / 2 => return None,
+            Some(last) => since(last),
+        };
+        self.last = Some(minute_of_week);
+        let &(_, setpoint) = self.starts
+            .iter()
+            .filter(|&&(s, _)| since(s) < elapsed)
+            .min_by_key(|&&(s, _)| since(s))?;
+        self.overridden = false;
+        Some(setpoint)
+    }
+}
+
+/// Schedules replaced through the API, by thermostat id, persisted so that they survive a
+/// restart.
+pub struct ScheduleStore {
+    path: String,
+    schedules: Mutex<HashMap<String, Vec<Slot>>>,
+}
+
+impl ScheduleStore {
+    /// Loads the schedules saved at the given path, if any.
+    ///
+    /// A store that cannot be read is discarded, and the configured schedules used instead.
+    pub fn load(path: &str) -> ScheduleStore {
+        let schedules = if Path::new(path).exists() {
+            File::open(path)
+                .map_err(|e| format!("could not open {}: {}", path, e))
+                .and_then(|file| {
+                    serde_json::from_reader(file)
+                        .map_err(|e| format!("could not parse {}: {}", path, e))
+                })
+                .unwrap_or_else(|err| {
+                    error!("{}", err);
+                    HashMap::new()
+                })
+        } else {
+            HashMap::new()
+        };
+        ScheduleStore {
+            path: path.to_string(),
+            schedules: Mutex::new(schedules),
+        }
+    }
+
+    /// Returns the saved schedule of a thermostat, if any.
+    pub fn get(&self, id: &str) -> Option<Vec<Slot>> {
+        self.schedules.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn set(&self, id: &str, slots: Vec<Slot>) {
+        let mut schedules = self.schedules.lock().unwrap();
+        schedules.insert(id.to_string(), slots);
+        self.save(&schedules);
+    }
+
+    fn save(&self, schedules: &HashMap<String, Vec<Slot>>) {
+        let tmp_path = format!("{}.tmp", self.path);
+        let result = File::create(&tmp_path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                serde_json::to_writer_pretty(file, schedules).map_err(|e| e.to_string())
+            })
+            .and_then(|_| fs::rename(&tmp_path, &self.path).map_err(|e| e.to_string()));
+        if let Err(err) = result {
+            error!("could not save schedules to {}: {}", self.path, err);
+        }
+    }
+}
+
+/// Returns the number of minutes since Monday 00:00 in local time, taking DST into account.
+pub fn local_minute_of_week() -> u32 {
+    let now = time::now();
+    // tm_wday counts from Sunday.
+    let day = (now.tm_wday as u32 + 6) % 7;
+    (day * MINUTES_PER_DAY + now.tm_hour as u32 * 60 + now.tm_min as u32) % MINUTES_PER_WEEK
+}
+
+#[get("/thermostats/<id>/schedule")]
+fn get_schedule(id: String, _user: User, hub: State<Hub>) -> Option<Json<ScheduleState>> {
+    hub.thermostat(&id).map(|t| Json(t.lock().unwrap().schedule.state()))
+}
+
+/// Replaces the schedule of a thermostat, and saves it; the slot currently in effect is applied
+/// immediately.
+#[put("/thermostats/<id>/schedule", format = "application/json", data = "<slots>")]
+fn put_schedule(
+    id: String,
+    slots: Json<Vec<Slot>>,
+    _user: User,
+    hub: State<Hub>,
+) -> Result<Json<ScheduleState>, Custom<String>> {
+    let thermostat = hub.thermostat(&id)
+        .ok_or(Custom(Status::NotFound, format!("no thermostat {:?}", id)))?;
+    let mut thermostat = thermostat.lock().unwrap();
+    let schedule = Schedule::with_limits(slots.0.clone(), &thermostat.limits)
+        .map_err(|e| Custom(Status::BadRequest, e))?;
+    thermostat.schedule = schedule;
+    hub.schedule_store.set(&id, slots.0);
+    thermostat.run_schedule(local_minute_of_week());
+    Ok(Json(thermostat.schedule.state()))
+}
+
+#[test]
+fn test_schedule() {
+    let slot = |days: &str, time: &str, setpoint| Slot {
+        days: days.to_string(),
+        time: time.to_string(),
+        setpoint: setpoint,
+    };
+    let mut schedule = Schedule::new(vec![
+        slot("weekdays", "06:30", 21.0),
+        slot("weekdays", "08:30", 17.0),
+        slot("sat,sun", "09:00", 20.0),
+    ]).unwrap();
+    let at = |day: u32, hour: u32, minute: u32| day * MINUTES_PER_DAY + hour * 60 + minute;
+
+    // Monday 00:00 is still in Sunday's slot.
+    assert_eq!(Some(20.0), schedule.update(at(0, 0, 0)));
+    assert_eq!(None, schedule.update(at(0, 6, 29)));
+    assert_eq!(Some(21.0), schedule.update(at(0, 6, 30)));
+    // A manual change is kept until the next slot.
+    schedule.set_overridden();
+    assert_eq!(None, schedule.update(at(0, 7, 0)));
+    assert!(schedule.state().overridden);
+    assert_eq!(Some(17.0), schedule.update(at(0, 8, 30)));
+    assert!(!schedule.state().overridden);
+    // Skipped slots are not applied, only the last one that started.
+    assert_eq!(Some(21.0), schedule.update(at(2, 7, 0)));
+    assert_eq!(Some(20.0), schedule.update(at(5, 12, 0)));
+    assert_eq!(Some(20.0), schedule.update(at(6, 23, 59)));
+    // The week wraps around.
+    assert_eq!(Some(21.0), schedule.update(at(0, 6, 30)));
+
+    assert!(Schedule::new(vec![slot("mon", "24:00", 20.0)]).is_err());
+    assert!(Schedule::new(vec![slot("monday", "06:00", 20.0)]).is_err());
+    let overlapping = vec![slot("daily", "06:00", 20.0), slot("mon", "06:00", 21.0)];
+    assert!(Schedule::new(overlapping).is_err());
+    assert_eq!(None, Schedule::default().update(at(0, 0, 0)));
+}
+
+#[test]
+fn test_schedule_dst() {
+    let slot = |time: &str, setpoint| Slot {
+        days: "daily".to_string(),
+        time: time.to_string(),
+        setpoint: setpoint,
+    };
+    let mut schedule = Schedule::new(vec![slot("02:30", 18.0), slot("08:00", 20.0)]).unwrap();
+    let at = |hour: u32, minute: u32| 6 * MINUTES_PER_DAY + hour * 60 + minute;
+
+    assert_eq!(Some(20.0), schedule.update(at(2, 0)));
+    assert_eq!(Some(18.0), schedule.update(at(2, 30)));
+    schedule.set_overridden();
+    // When DST ends, 02:00 to 03:00 happens twice; the slot is not applied again.
+    assert_eq!(None, schedule.update(at(2, 59)));
+    assert_eq!(None, schedule.update(at(2, 0)));
+    assert_eq!(None, schedule.update(at(2, 30)));
+    assert!(schedule.state().overridden);
+    assert_eq!(None, schedule.update(at(3, 0)));
+    assert_eq!(Some(20.0), schedule.update(at(8, 0)));
+    // When DST starts, 02:00 to 03:00 is skipped; the slot still takes effect.
+    let mut schedule = Schedule::new(vec![slot("02:30", 18.0)]).unwrap();
+    assert_eq!(Some(18.0), schedule.update(at(1, 59)));
+    assert_eq!(Some(18.0), schedule.update(at(3, 0)));
+}
+
+#[test]
+fn test_schedule_store() {
+    use testing::TempDir;
+
+    let dir = TempDir::new("schedule");
+    let path = dir.file("schedules.json");
+    let slots = vec![
+        Slot {
+            days: "daily".to_string(),
+            time: "06:30".to_string(),
+            setpoint: 21.0,
+        },
+    ];
+    ScheduleStore::load(&path).set("66", slots.clone());
+    let store = ScheduleStore::load(&path);
+    assert_eq!(Some(slots), store.get("66"));
+    assert_eq!(None, store.get("67"));
+}
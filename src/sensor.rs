@@ -0,0 +1,272 @@
+use std::fs::File;
+use std::io::Read;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use reqwest;
+use serde_json;
+
+use thermostat::Thermostat;
+
+/// Physical quantity measured by a sensor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantity {
+    Temperature,
+    Humidity,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SourceConfig {
+    /// `w1_slave` file of a 1-Wire DS18B20 sensor, e.g.
+    /// `/sys/bus/w1/devices/28-000005e2fdc3/w1_slave`.
+    W1 { path: String },
+    /// File containing a single number.
+    File {
+        path: String,
+        #[serde(default = "default_scale")]
+        scale: f32,
+    },
+    /// Command printing a single number on its standard output.
+    Command {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default = "default_scale")]
+        scale: f32,
+    },
+    /// HTTP endpoint returning a JSON document, in which the number is found at `pointer` (e.g.
+    /// `/sensors/0/temperature`).
+    Http {
+        url: String,
+        pointer: String,
+        #[serde(default = "default_scale")]
+        scale: f32,
+    },
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+impl SourceConfig {
+    /// Takes a single reading.
+    pub fn read(&self) -> Result<f32, String> {
+        match self {
+            &SourceConfig::W1 { ref path } => parse_w1(&read_file(path)?),
+            &SourceConfig::File { ref path, scale } => parse_number(&read_file(path)?, scale),
+            &SourceConfig::Command {
+                ref command,
+                ref args,
+                scale,
+            } => {
+                let output = Command::new(command)
+                    .args(args)
+                    .output()
+                    .map_err(|e| format!("could not run {}: {}", command, e))?;
+                if !output.status.success() {
+                    return Err(format!("{} exited with {}", command, output.status));
+                }
+                parse_number(&String::from_utf8_lossy(&output.stdout), scale)
+            }
+            &SourceConfig::Http {
+                ref url,
+                ref pointer,
+                scale,
+            } => {
+                let value: serde_json::Value = reqwest::get(url.as_str())
+                    .and_then(|mut res| res.json())
+                    .map_err(|e| format!("could not fetch {}: {}", url, e))?;
+                value
+                    .pointer(pointer)
+                    .and_then(|v| v.as_f64())
+                    .map(|v| v as f32 * scale)
+                    .ok_or(format!("no number at {} in {}", pointer, url))
+            }
+        }
+    }
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    let mut s = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut s))
+        .map_err(|e| format!("could not read {}: {}", path, e))?;
+    Ok(s)
+}
+
+fn parse_number(s: &str, scale: f32) -> Result<f32, String> {
+    s.trim()
+        .parse::<f32>()
+        .map(|v| v * scale)
+        .map_err(|e| format!("invalid reading {:?}: {}", s.trim(), e))
+}
+
+/// Parses the contents of a DS18B20 `w1_slave` file, in which the first line ends with the result
+/// of the CRC check and the second with the temperature in thousandths of degrees Celsius.
+fn parse_w1(s: &str) -> Result<f32, String> {
+    let mut lines = s.lines();
+    match lines.next() {
+        Some(l) if l.trim().ends_with("YES") => {}
+        _ => return Err(format!("w1 crc check failed: {:?}", s)),
+    }
+    match lines.next().and_then(|l| l.rfind("t=").map(|i| &l[i + 2..])) {
+        Some(t) => parse_number(t, 1.0).map(|t| t / 1000.0),
+        None => Err(format!("no w1 temperature: {:?}", s)),
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SensorConfig {
+    pub source: SourceConfig,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// Weight of each new reading in the exponential moving average of readings; 1 disables
+    /// smoothing.
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f32,
+    /// Age after which the last reading is considered stale, and the device offline.
+    #[serde(default = "default_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+fn default_interval_secs() -> u64 {
+    10
+}
+
+fn default_smoothing() -> f32 {
+    0.3
+}
+
+fn default_max_age_secs() -> u64 {
+    120
+}
+
+impl SensorConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.smoothing <= 0.0 || self.smoothing > 1.0 {
+            return Err("sensor smoothing must be in (0, 1]".to_string());
+        }
+        if self.interval_secs == 0 || self.max_age_secs < self.interval_secs {
+            return Err("sensor max_age_secs must be at least interval_secs".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Smoothed value of a sensor, along with the time of the last reading.
+pub struct Sensor {
+    pub config: SensorConfig,
+    value: Option<f32>,
+    last_reading: Option<Instant>,
+}
+
+impl Sensor {
+    pub fn new(config: SensorConfig) -> Sensor {
+        Sensor {
+            config: config,
+            value: None,
+            last_reading: None,
+        }
+    }
+
+    /// Records a new reading, returning the smoothed value.
+    pub fn update(&mut self, reading: f32, now: Instant) -> f32 {
+        let value = match self.value {
+            Some(v) if !self.is_stale(now) => v + self.config.smoothing * (reading - v),
+            _ => reading,
+        };
+        self.value = Some(value);
+        self.last_reading = Some(now);
+        value
+    }
+
+    pub fn is_stale(&self, now: Instant) -> bool {
+        match self.last_reading {
+            Some(t) => now.duration_since(t) > Duration::from_secs(self.config.max_age_secs),
+            None => true,
+        }
+    }
+}
+
+/// Starts polling the sensors of a thermostat, each from its own thread.
+pub fn watch(thermostat: Arc<Mutex<Thermostat>>) {
+    let sensors = {
+        let t = thermostat.lock().unwrap();
+        let mut sensors = vec![];
+        if let Some(ref s) = t.temperature_sensor {
+            sensors.push((Quantity::Temperature, s.config.clone()));
+        }
+        if let Some(ref s) = t.humidity_sensor {
+            sensors.push((Quantity::Humidity, s.config.clone()));
+        }
+        sensors
+    };
+    for (quantity, config) in sensors {
+        let thermostat = thermostat.clone();
+        thread::spawn(move || loop {
+            // Read without holding the lock, as sources may be slow.
+            let reading = config.source.read();
+            match thermostat.lock() {
+                Ok(mut thermostat) => thermostat.record(quantity, reading, Instant::now()),
+                Err(err) => error!("could not lock thermostat mutex: {:?}", err),
+            }
+            thread::sleep(Duration::from_secs(config.interval_secs));
+        });
+    }
+}
+
+#[test]
+fn test_parse_w1() {
+    assert_eq!(
+        Ok(23.125),
+        parse_w1(
+            "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n72 01 4b 46 7f ff 0e 10 57 t=23125\n"
+        )
+    );
+    assert!(parse_w1("72 01 4b 46 7f ff 0e 10 57 : crc=00 NO\n72 01 4b t=23125\n").is_err());
+}
+
+#[test]
+fn test_sources() {
+    use std::io::Write;
+    use testing::TempDir;
+
+    let dir = TempDir::new("sensor");
+    let path = dir.file("temperature");
+    File::create(&path).unwrap().write_all(b"43\n").unwrap();
+    let source = SourceConfig::File {
+        path: path,
+        scale: 0.5,
+    };
+    assert_eq!(Ok(21.5), source.read());
+
+    let source = SourceConfig::Command {
+        command: "echo".to_string(),
+        args: vec!["42".to_string()],
+        scale: 1.0,
+    };
+    assert_eq!(Ok(42.0), source.read());
+}
+
+#[test]
+fn test_sensor() {
+    let mut sensor = Sensor::new(SensorConfig {
+        source: SourceConfig::W1 {
+            path: "".to_string(),
+        },
+        interval_secs: 10,
+        smoothing: 0.5,
+        max_age_secs: 30,
+    });
+    let t0 = Instant::now();
+    assert!(sensor.is_stale(t0));
+    assert_eq!(20.0, sensor.update(20.0, t0));
+    assert_eq!(21.0, sensor.update(22.0, t0 + Duration::from_secs(10)));
+    assert!(!sensor.is_stale(t0 + Duration::from_secs(40)));
+    assert!(sensor.is_stale(t0 + Duration::from_secs(41)));
+    // Stale values are not averaged with new readings.
+    assert_eq!(30.0, sensor.update(30.0, t0 + Duration::from_secs(50)));
+}
@@ -0,0 +1,115 @@
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use rand;
+use serde_json;
+
+use config::Config;
+use hub::Hub;
+
+/// Temporary directory unique to a test, so that tests running in parallel, or concurrent test
+/// runs on the same host, do not share files. It is removed when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!(
+            "smartlights-test-{}-{:016x}",
+            name,
+            rand::random::<u64>()
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path: path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the path of a file in the directory, as a string.
+    pub fn file(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Builds a hub from the given JSON configuration, which must be valid.
+pub fn build_hub(json: &str) -> Hub {
+    let config: Config = serde_json::from_str(json).unwrap();
+    config.validate().unwrap();
+    config.build().unwrap()
+}
+
+/// Starts an HTTP server answering every request with a JSON body computed by `respond` from the
+/// body of the request, or with an error if `respond` returns `None`.
+///
+/// Returns the base URL of the server, and a receiver for the requests it handled, in full.
+pub fn mock_server<F>(respond: F) -> (String, mpsc::Receiver<String>)
+where
+    F: Fn(&str) -> Option<String> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            // Read until the end of the headers, then until the end of the body.
+            let body_start = loop {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break request.len();
+                }
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some(i) = text.find("\r\n\r\n") {
+                    let content_length = text[..i]
+                        .lines()
+                        .filter_map(|l| {
+                            let mut parts = l.splitn(2, ':');
+                            match (parts.next(), parts.next()) {
+                                (Some(k), Some(v)) if k.eq_ignore_ascii_case("content-length") => {
+                                    v.trim().parse::<usize>().ok()
+                                }
+                                _ => None,
+                            }
+                        })
+                        .next()
+                        .unwrap_or(0);
+                    if request.len() >= i + 4 + content_length {
+                        break i + 4;
+                    }
+                }
+            };
+            let request = String::from_utf8_lossy(&request).to_string();
+            let response = match respond(&request[body_start..]) {
+                Some(body) => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                ),
+                None => "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\
+                         Connection: close\r\n\r\n"
+                    .to_string(),
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+            let _ = sender.send(request);
+        }
+    });
+    (url, receiver)
+}
@@ -1,20 +1,38 @@
-use device::Device;
-use google_actions::{ExecuteResponseCommand, Name, Params, SyncResponseDevice,
-                     SyncResponseDeviceAttributes};
+use device::{Device, Metadata};
+use google_actions::{DeviceCommand, ErrorCode, Params, SyncResponseDevice,
+                     SyncResponseDeviceAttributes, ThermostatTemperatureRange};
 use std::str::FromStr;
 use std::string::ToString;
+use std::sync::Arc;
+use std::time::Instant;
 
 use google_actions;
+use homegraph::HomeGraph;
+use hvac::{Activity, Controller, HvacOutput};
+use schedule::Schedule;
+use sensor::{Quantity, Sensor};
 
 pub struct Thermostat {
     pub id: String,
     pub name: String,
+    pub metadata: Metadata,
     pub available_thermostat_modes: Vec<ThermostatMode>,
     pub thermostat_temperature_unit: TemperatureUnit,
+    pub limits: SetpointLimits,
     pub status: ThermostatStatus,
+    pub controller: Controller,
+    pub output: Box<HvacOutput>,
+    /// Sensors updating the ambient temperature and humidity, if any.
+    pub temperature_sensor: Option<Sensor>,
+    pub humidity_sensor: Option<Sensor>,
+    pub schedule: Schedule,
+    pub homegraph: Option<Arc<HomeGraph>>,
+    /// Ambient temperature and humidity last reported to HomeGraph, if any.
+    pub reported_ambient: Option<(f32, f32)>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum ThermostatMode {
     Off,
     Heat,
@@ -36,7 +54,7 @@ impl ToString for ThermostatMode {
 }
 
 impl FromStr for ThermostatMode {
-    type Err = u8;
+    type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "off" => Ok(ThermostatMode::Off),
@@ -44,18 +62,92 @@ impl FromStr for ThermostatMode {
             "cool" => Ok(ThermostatMode::Cool),
             "on" => Ok(ThermostatMode::On),
             "heatcool" => Ok(ThermostatMode::Heatcool),
-            _ => Err(1),
+            _ => Err(format!("unknown thermostat mode {:?}", s)),
         }
     }
 }
 
+/// Setpoints accepted by a thermostat, in Celsius.
 #[derive(Debug, Clone)]
-#[allow(unused)]
+pub struct SetpointLimits {
+    pub min: f32,
+    pub max: f32,
+    /// Minimum distance between the low and high setpoints of a range.
+    pub min_range: f32,
+}
+
+impl SetpointLimits {
+    pub fn check(&self, setpoint: f32) -> Result<(), ErrorCode> {
+        if setpoint.is_nan() || setpoint < self.min || setpoint > self.max {
+            return Err(ErrorCode::ValueOutOfRange);
+        }
+        Ok(())
+    }
+
+    pub fn check_range(&self, low: f32, high: f32) -> Result<(), ErrorCode> {
+        self.check(low)?;
+        self.check(high)?;
+        if high - low < self.min_range {
+            return Err(ErrorCode::RangeTooClose);
+        }
+        Ok(())
+    }
+}
+
+/// Smallest change of ambient humidity shown, in percent.
+const HUMIDITY_RESOLUTION: f32 = 1.0;
+
+/// Unit in which temperatures are shown to users.
+///
+/// Temperatures are always stored in Celsius, which is also what the Google protocol uses.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub enum TemperatureUnit {
     C,
     F,
 }
 
+impl TemperatureUnit {
+    pub fn from_celsius(&self, c: f32) -> f32 {
+        match self {
+            &TemperatureUnit::C => c,
+            &TemperatureUnit::F => c * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    pub fn to_celsius(&self, t: f32) -> f32 {
+        match self {
+            &TemperatureUnit::C => t,
+            &TemperatureUnit::F => (t - 32.0) * 5.0 / 9.0,
+        }
+    }
+
+    /// Rounds a temperature in Celsius to the nearest step of the unit: half degrees Celsius, or
+    /// whole degrees Fahrenheit.
+    pub fn round(&self, c: f32) -> f32 {
+        match self {
+            &TemperatureUnit::C => (c * 2.0).round() / 2.0,
+            &TemperatureUnit::F => self.to_celsius(self.from_celsius(c).round()),
+        }
+    }
+
+    /// Smallest change of temperature in Celsius shown in the unit.
+    pub fn resolution(&self) -> f32 {
+        match self {
+            &TemperatureUnit::C => 0.5,
+            &TemperatureUnit::F => 5.0 / 9.0,
+        }
+    }
+
+    /// Formats a temperature in Celsius for display, e.g. "21.5°C" or "71°F".
+    pub fn format(&self, c: f32) -> String {
+        let rounded = self.from_celsius(self.round(c));
+        match self {
+            &TemperatureUnit::C => format!("{}°C", rounded),
+            &TemperatureUnit::F => format!("{}°F", rounded.round()),
+        }
+    }
+}
+
 impl ToString for TemperatureUnit {
     fn to_string(&self) -> String {
         match self {
@@ -67,18 +159,24 @@ impl ToString for TemperatureUnit {
 
 #[derive(Debug, Clone)]
 pub struct ThermostatStatus {
+    /// Whether the readings of all the sensors are fresh.
+    pub online: bool,
     pub mode: ThermostatMode,
     pub temperature_setpoint: f32,
     pub temperature_ambient: f32,
     pub temperature_setpoint_low: f32,
     pub temperature_setpoint_high: f32,
     pub humidity_ambient: f32,
+    /// What the equipment is currently doing, as decided by the controller.
+    pub activity: Activity,
 }
 
 impl Into<google_actions::Params> for ThermostatStatus {
     fn into(self) -> google_actions::Params {
         google_actions::Params {
+            online: Some(self.online),
             thermostat_mode: Some(self.mode.to_string()),
+            active_thermostat_mode: Some(self.activity.to_string()),
             thermostat_temperature_ambient: Some(self.temperature_ambient),
             thermostat_humidity_ambient: Some(self.humidity_ambient),
             thermostat_temperature_setpoint: Some(self.temperature_setpoint),
@@ -90,26 +188,47 @@ impl Into<google_actions::Params> for ThermostatStatus {
 }
 
 impl Thermostat {
-    pub fn temperature_setpoint(&mut self, setpoint: f32) {
+    pub fn temperature_setpoint(&mut self, setpoint: f32) -> Result<(), ErrorCode> {
         debug!("temperature_setpoint: {:?}", setpoint);
+        let setpoint = self.thermostat_temperature_unit.round(setpoint);
+        self.limits.check(setpoint)?;
         self.status.temperature_setpoint = setpoint;
-        if self.status.mode == ThermostatMode::Off {
-            self.status.mode = ThermostatMode::On;
-        }
+        self.turn_on(&[ThermostatMode::On, ThermostatMode::Heat, ThermostatMode::Cool]);
         self.output();
+        Ok(())
     }
 
-    pub fn temperature_set_range(&mut self, setpoint_low: f32, setpoint_high: f32) {
+    pub fn temperature_set_range(
+        &mut self,
+        setpoint_low: f32,
+        setpoint_high: f32,
+    ) -> Result<(), ErrorCode> {
         debug!(
             "temperature_set_range: {:?} - {:?}",
             setpoint_low, setpoint_high
         );
+        let setpoint_low = self.thermostat_temperature_unit.round(setpoint_low);
+        let setpoint_high = self.thermostat_temperature_unit.round(setpoint_high);
+        self.limits.check_range(setpoint_low, setpoint_high)?;
         self.status.temperature_setpoint_low = setpoint_low;
         self.status.temperature_setpoint_high = setpoint_high;
-        if self.status.mode == ThermostatMode::Off {
-            self.status.mode = ThermostatMode::On;
-        }
+        self.turn_on(&[ThermostatMode::Heatcool]);
         self.output();
+        Ok(())
+    }
+
+    /// Switches a thermostat that is off to the first of the given modes that it supports, if
+    /// any, so that changing a setpoint takes effect.
+    fn turn_on(&mut self, modes: &[ThermostatMode]) {
+        if self.status.mode != ThermostatMode::Off {
+            return;
+        }
+        if let Some(mode) = modes
+            .iter()
+            .find(|m| self.available_thermostat_modes.contains(m))
+        {
+            self.status.mode = mode.clone();
+        }
     }
 
     pub fn thermostat_set_mode(&mut self, mode: ThermostatMode) {
@@ -118,8 +237,144 @@ impl Thermostat {
         self.output();
     }
 
+    /// Applies the setpoint of the schedule slot in effect at the given minute of the week, if it
+    /// just started.
+    ///
+    /// In heatcool mode, the low and high setpoints are moved so that the range is centered on
+    /// the scheduled setpoint, keeping its width.
+    pub fn run_schedule(&mut self, minute_of_week: u32) {
+        if let Some(setpoint) = self.schedule.update(minute_of_week) {
+            info!("thermostat {:?} scheduled setpoint: {}", self.id, setpoint);
+            let unit = self.thermostat_temperature_unit.clone();
+            let setpoint = unit.round(setpoint);
+            let width =
+                self.status.temperature_setpoint_high - self.status.temperature_setpoint_low;
+            let low = unit.round(setpoint - width / 2.0);
+            let high = low + width;
+            let heatcool = self.status.mode == ThermostatMode::Heatcool;
+            let result = self.limits.check(setpoint).and_then(|_| {
+                if heatcool {
+                    self.limits.check_range(low, high)
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(err) = result {
+                error!("thermostat {:?} scheduled setpoint: {:?}", self.id, err);
+                return;
+            }
+            self.status.temperature_setpoint = setpoint;
+            if heatcool {
+                self.status.temperature_setpoint_low = low;
+                self.status.temperature_setpoint_high = high;
+            }
+            self.output();
+        }
+    }
+
+    /// Runs the control loop once, driving the output if the activity changes.
+    ///
+    /// Returns whether the activity or the online status changed. This must be called
+    /// periodically, as the ambient temperature changes independently of any command.
+    pub fn control(&mut self) -> bool {
+        let now = Instant::now();
+        let fresh = |sensor: &Option<Sensor>| sensor.as_ref().map_or(true, |s| !s.is_stale(now));
+        let online = fresh(&self.temperature_sensor) && fresh(&self.humidity_sensor);
+        let online_changed = online != self.status.online;
+        if online_changed {
+            warn!("thermostat {:?} online: {}", self.id, online);
+            self.status.online = online;
+        }
+
+        // Stop heating or cooling altogether while the ambient temperature is unknown.
+        let status = if online {
+            self.status.clone()
+        } else {
+            ThermostatStatus {
+                mode: ThermostatMode::Off,
+                ..self.status.clone()
+            }
+        };
+        let activity = self.controller.decide(
+            &status,
+            self.output.can_heat(),
+            self.output.can_cool(),
+            now,
+        );
+        if activity == self.status.activity {
+            return online_changed;
+        }
+        info!("thermostat {:?}: {:?}", self.id, activity);
+        // The activity is only recorded once the equipment switched, so that it is retried on the
+        // next call otherwise.
+        if let Err(err) = self.output.set(activity) {
+            error!("could not set thermostat {:?} output: {}", self.id, err);
+            return online_changed;
+        }
+        self.controller.commit(activity, now);
+        self.status.activity = activity;
+        true
+    }
+
+    /// Records a reading from one of the sensors, reporting the state if the ambient temperature
+    /// or humidity changed visibly since it was last reported.
+    pub fn record(&mut self, quantity: Quantity, reading: Result<f32, String>, now: Instant) {
+        let reading = match reading {
+            Ok(reading) => reading,
+            Err(err) => {
+                error!("thermostat {:?} {:?} sensor: {}", self.id, quantity, err);
+                return;
+            }
+        };
+        debug!("thermostat {:?} {:?} reading: {}", self.id, quantity, reading);
+        match quantity {
+            Quantity::Temperature => if let Some(ref mut sensor) = self.temperature_sensor {
+                self.status.temperature_ambient = sensor.update(reading, now);
+            },
+            Quantity::Humidity => if let Some(ref mut sensor) = self.humidity_sensor {
+                self.status.humidity_ambient = sensor.update(reading, now);
+            },
+        }
+        if self.ambient_changed() {
+            self.report_state();
+        }
+    }
+
+    /// Returns whether the ambient temperature or humidity moved by at least the resolution at
+    /// which they are shown since they were last reported.
+    fn ambient_changed(&self) -> bool {
+        match self.reported_ambient {
+            Some((temperature, humidity)) => {
+                (self.status.temperature_ambient - temperature).abs()
+                    >= self.thermostat_temperature_unit.resolution()
+                    || (self.status.humidity_ambient - humidity).abs() >= HUMIDITY_RESOLUTION
+            }
+            None => true,
+        }
+    }
+
+    /// Text shown on the display: the setpoint, or dashes while the thermostat is off.
+    pub fn display_text(&self) -> String {
+        let unit = &self.thermostat_temperature_unit;
+        match self.status.mode {
+            ThermostatMode::Off => format!("--°{}", unit.to_string()),
+            _ => unit.format(self.status.temperature_setpoint),
+        }
+    }
+
+    pub fn report_state(&mut self) {
+        if let Some(ref homegraph) = self.homegraph {
+            homegraph.report_state(&self.id, self.status.clone().into());
+        }
+        self.reported_ambient = Some((
+            self.status.temperature_ambient,
+            self.status.humidity_ambient,
+        ));
+    }
+
     fn output(&mut self) {
-        // TODO
+        self.control();
+        self.report_state();
     }
 }
 
@@ -128,21 +383,16 @@ impl Device for Thermostat {
         self.id.clone()
     }
 
-    fn sync(&self) -> Option<SyncResponseDevice> {
-        Option::Some(SyncResponseDevice {
+    fn sync(&self) -> SyncResponseDevice {
+        SyncResponseDevice {
             id: self.id(),
             type_: "action.devices.types.THERMOSTAT".to_string(),
             traits: vec!["action.devices.traits.TemperatureSetting".to_string()],
-            name: Name {
-                default_name: vec![self.name.to_string()],
-                name: Some(self.name.clone()),
-                nicknames: vec![],
-            },
-            // TODO: attributes.
-            will_report_state: false,
-            device_info: None,
-            room_hint: None,
-            structure_hint: None,
+            name: self.metadata.name(&self.name),
+            will_report_state: self.homegraph.is_some(),
+            device_info: self.metadata.device_info.clone(),
+            room_hint: self.metadata.room_hint.clone(),
+            structure_hint: self.metadata.structure_hint.clone(),
             attributes: Some(SyncResponseDeviceAttributes {
                 available_thermostat_modes: Some(
                     self.available_thermostat_modes
@@ -152,34 +402,234 @@ impl Device for Thermostat {
                         .join(","),
                 ),
                 thermostat_temperature_unit: Some(self.thermostat_temperature_unit.to_string()),
+                thermostat_temperature_range: Some(ThermostatTemperatureRange {
+                    min_threshold_celsius: self.limits.min,
+                    max_threshold_celsius: self.limits.max,
+                }),
                 ..SyncResponseDeviceAttributes::default()
             }),
-        })
+        }
     }
 
-    fn query(&self) -> Option<Params> {
-        Option::Some(self.status.clone().into())
+    fn query(&self) -> Result<Params, ErrorCode> {
+        if !self.status.online {
+            return Err(ErrorCode::DeviceOffline);
+        }
+        Ok(self.status.clone().into())
     }
 
-    fn execute(&mut self, params: &Params) -> Option<ExecuteResponseCommand> {
-        if let Some(s) = params.thermostat_temperature_setpoint {
-            self.temperature_setpoint(s);
-        }
-        if let (Some(low), Some(high)) = (
-            params.thermostat_temperature_setpoint_low,
-            params.thermostat_temperature_setpoint_high,
-        ) {
-            self.temperature_set_range(low, high);
+    fn execute(&mut self, command: &DeviceCommand) -> Result<Params, ErrorCode> {
+        // Mode changes are still accepted while the sensors are stale, so that the thermostat can
+        // be turned off when it misbehaves.
+        let set_mode = match command {
+            &DeviceCommand::ThermostatSetMode { .. } => true,
+            _ => false,
+        };
+        if !self.status.online && !set_mode {
+            return Err(ErrorCode::DeviceOffline);
         }
-        if let Some(ref mode) = params.thermostat_mode {
-            if let Ok(mode) = ThermostatMode::from_str(mode) {
-                self.thermostat_set_mode(mode);
+        match command {
+            &DeviceCommand::ThermostatTemperatureSetpoint { setpoint } => {
+                self.temperature_setpoint(setpoint)?;
+                self.schedule.set_overridden();
+            }
+            &DeviceCommand::ThermostatTemperatureSetRange { low, high } => {
+                self.temperature_set_range(low, high)?;
+                self.schedule.set_overridden();
             }
+            &DeviceCommand::ThermostatSetMode { ref mode } => match ThermostatMode::from_str(mode) {
+                Ok(ref mode) if self.available_thermostat_modes.contains(mode) => {
+                    self.thermostat_set_mode(mode.clone())
+                }
+                Ok(mode) => {
+                    debug!("thermostat {:?} does not support {:?}", self.id, mode);
+                    return Err(ErrorCode::NotSupported);
+                }
+                Err(err) => {
+                    debug!("{}", err);
+                    return Err(ErrorCode::NotSupported);
+                }
+            },
+            _ => return Err(ErrorCode::NotSupported),
         }
-        Option::Some(ExecuteResponseCommand {
-            ids: vec![self.id()],
-            status: "SUCCESS".to_string(),
-            states: self.status.clone().into(),
+        Ok(self.status.clone().into())
+    }
+}
+
+#[test]
+fn test_temperature_unit() {
+    let f = TemperatureUnit::F;
+    assert_eq!(212.0, f.from_celsius(100.0));
+    assert_eq!(-40.0, f.to_celsius(-40.0));
+    // 70°F sent by Google as 21.11°C.
+    assert_eq!("70°F", f.format(21.11));
+    assert_eq!(70.0, f.from_celsius(f.round(21.11)).round());
+    assert_eq!(21.5, TemperatureUnit::C.round(21.4));
+    assert_eq!("21.5°C", TemperatureUnit::C.format(21.4));
+    assert_eq!("21°C", TemperatureUnit::C.format(21.1));
+}
+
+#[test]
+fn test_setpoint_limits() {
+    let limits = SetpointLimits {
+        min: 10.0,
+        max: 30.0,
+        min_range: 2.0,
+    };
+    assert_eq!(Ok(()), limits.check(21.0));
+    assert_eq!(Err(ErrorCode::ValueOutOfRange), limits.check(9.5));
+    assert_eq!(Err(ErrorCode::ValueOutOfRange), limits.check(::std::f32::NAN));
+    assert_eq!(Ok(()), limits.check_range(18.0, 20.0));
+    assert_eq!(Err(ErrorCode::RangeTooClose), limits.check_range(19.0, 20.0));
+    assert_eq!(Err(ErrorCode::RangeTooClose), limits.check_range(22.0, 20.0));
+    assert_eq!(Err(ErrorCode::ValueOutOfRange), limits.check_range(18.0, 31.0));
+    assert!(ThermostatMode::from_str("eco").is_err());
+}
+
+#[test]
+fn test_record_reports_ambient_changes() {
+    use testing::build_hub;
+
+    let hub = build_hub(
+        r#"
+{
+  "thermostats": [{
+    "id": "66",
+    "name": "Thermostat",
+    "modes": ["off", "heat"],
+    "unit": "F",
+    "temperature_sensor": {
+      "source": { "type": "file", "path": "/nonexistent" },
+      "smoothing": 1
+    }
+  }]
+}
+"#,
+    );
+    let mut thermostat = hub.thermostats[0].lock().unwrap();
+    let now = Instant::now();
+
+    thermostat.record(Quantity::Temperature, Ok(20.0), now);
+    assert_eq!(Some((20.0, 50.0)), thermostat.reported_ambient);
+    // Less than 1°F.
+    thermostat.record(Quantity::Temperature, Ok(20.5), now);
+    assert_eq!(Some((20.0, 50.0)), thermostat.reported_ambient);
+    thermostat.record(Quantity::Temperature, Ok(19.0), now);
+    assert_eq!(Some((19.0, 50.0)), thermostat.reported_ambient);
+    thermostat.record(Quantity::Temperature, Err("timeout".to_string()), now);
+    assert_eq!(Some((19.0, 50.0)), thermostat.reported_ambient);
+
+    assert_eq!("--°F", thermostat.display_text());
+    thermostat.thermostat_set_mode(ThermostatMode::Heat);
+    assert_eq!("70°F", thermostat.display_text());
+}
+
+#[test]
+fn test_execute_while_offline() {
+    use testing::build_hub;
+
+    let hub = build_hub(
+        r#"
+{
+  "thermostats": [{
+    "id": "66",
+    "name": "Thermostat",
+    "modes": ["off", "heat"],
+    "temperature_sensor": { "source": { "type": "file", "path": "/nonexistent" } }
+  }]
+}
+"#,
+    );
+    let mut thermostat = hub.thermostats[0].lock().unwrap();
+    thermostat.status.mode = ThermostatMode::Heat;
+    assert!(!thermostat.status.online);
+
+    assert_eq!(Err(ErrorCode::DeviceOffline), thermostat.query());
+    assert_eq!(
+        Err(ErrorCode::DeviceOffline),
+        thermostat.execute(&DeviceCommand::ThermostatTemperatureSetpoint { setpoint: 22.0 })
+    );
+    let params = thermostat
+        .execute(&DeviceCommand::ThermostatSetMode {
+            mode: "off".to_string(),
         })
+        .unwrap();
+    assert_eq!(Some(false), params.online);
+    assert_eq!(Some("off".to_string()), params.thermostat_mode);
+    assert_eq!(ThermostatMode::Off, thermostat.status.mode);
+}
+
+#[test]
+fn test_schedule_heats() {
+    use testing::build_hub;
+
+    let hub = build_hub(
+        r#"
+{
+  "thermostats": [{
+    "id": "66",
+    "name": "Heater",
+    "modes": ["off", "heat"],
+    "mode": "heat",
+    "setpoint": 15,
+    "schedule": [{ "days": "daily", "time": "06:00", "setpoint": 25 }]
+  }, {
+    "id": "67",
+    "name": "Heat pump",
+    "modes": ["off", "heatcool"],
+    "mode": "heatcool",
+    "setpoint_low": 15,
+    "setpoint_high": 17,
+    "schedule": [{ "days": "daily", "time": "06:00", "setpoint": 25 }]
+  }]
+}
+"#,
+    );
+    for thermostat in &hub.thermostats {
+        let mut thermostat = thermostat.lock().unwrap();
+        // The ambient temperature is 20°C until the first readings.
+        thermostat.control();
+        assert_eq!(Activity::Idle, thermostat.status.activity);
+
+        // Monday, 06:00.
+        thermostat.run_schedule(6 * 60);
+        thermostat.control();
+        assert_eq!(25.0, thermostat.status.temperature_setpoint);
+        assert_eq!(Activity::Heating, thermostat.status.activity);
     }
+    let thermostat = hub.thermostats[1].lock().unwrap();
+    assert_eq!(24.0, thermostat.status.temperature_setpoint_low);
+    assert_eq!(26.0, thermostat.status.temperature_setpoint_high);
+}
+
+#[test]
+fn test_setpoint_turns_on() {
+    use testing::build_hub;
+
+    let hub = build_hub(
+        r#"
+{
+  "thermostats": [{
+    "id": "66",
+    "name": "Heater",
+    "modes": ["off", "heat"]
+  }, {
+    "id": "67",
+    "name": "Heat pump",
+    "modes": ["off", "heatcool"]
+  }]
+}
+"#,
+    );
+    let mut heater = hub.thermostats[0].lock().unwrap();
+    heater.temperature_set_range(18.0, 22.0).unwrap();
+    assert_eq!(ThermostatMode::Off, heater.status.mode);
+    heater.temperature_setpoint(22.0).unwrap();
+    assert_eq!(ThermostatMode::Heat, heater.status.mode);
+
+    let mut heat_pump = hub.thermostats[1].lock().unwrap();
+    heat_pump.temperature_setpoint(22.0).unwrap();
+    assert_eq!(ThermostatMode::Off, heat_pump.status.mode);
+    heat_pump.temperature_set_range(18.0, 22.0).unwrap();
+    assert_eq!(ThermostatMode::Heatcool, heat_pump.status.mode);
 }